@@ -0,0 +1,12 @@
+CREATE TABLE "instances"
+(
+    id            BIGSERIAL   NOT NULL PRIMARY KEY,
+    domain        TEXT        NOT NULL CHECK ( length(domain) <= 253 ),
+    client_id     TEXT        NOT NULL,
+    client_secret TEXT        NOT NULL,
+    banned_until  TIMESTAMPTZ NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT ( now() ),
+    updated_at    TIMESTAMPTZ NOT NULL DEFAULT ( now() )
+);
+
+CREATE UNIQUE INDEX instances_domain_idx ON instances (domain);
@@ -0,0 +1,11 @@
+CREATE TABLE "users"
+(
+    id           BIGSERIAL   NOT NULL PRIMARY KEY,
+    instance_id  BIGINT      NOT NULL,
+    access_token TEXT        NOT NULL,
+    banned_until TIMESTAMPTZ NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT ( now() ),
+    updated_at   TIMESTAMPTZ NOT NULL DEFAULT ( now() ),
+
+    FOREIGN KEY (instance_id) REFERENCES instances (id)
+);
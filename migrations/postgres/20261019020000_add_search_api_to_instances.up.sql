@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN search_api TEXT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE instances ADD COLUMN banned_at TIMESTAMPTZ NULL;
+ALTER TABLE instances ADD COLUMN ban_reason TEXT NULL;
+ALTER TABLE users ADD COLUMN banned_at TIMESTAMPTZ NULL;
+ALTER TABLE users ADD COLUMN ban_reason TEXT NULL;
@@ -0,0 +1,12 @@
+CREATE TABLE "failures"
+(
+    id         BIGSERIAL   NOT NULL PRIMARY KEY,
+    domain     TEXT        NOT NULL,
+    user_id    BIGINT      NULL,
+    kind       TEXT        NOT NULL,
+    message    TEXT        NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT ( now() )
+);
+
+CREATE INDEX failures_domain_idx ON failures (domain);
+CREATE INDEX failures_created_at_idx ON failures (created_at);
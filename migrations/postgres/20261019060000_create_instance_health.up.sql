@@ -0,0 +1,11 @@
+CREATE TABLE "instance_health"
+(
+    id         BIGSERIAL   NOT NULL PRIMARY KEY,
+    domain     TEXT        NOT NULL,
+    up         BOOLEAN     NOT NULL,
+    message    TEXT        NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT ( now() )
+);
+
+CREATE INDEX instance_health_domain_idx ON instance_health (domain);
+CREATE INDEX instance_health_created_at_idx ON instance_health (created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN last_used_at;
+ALTER TABLE instances DROP COLUMN last_used_at;
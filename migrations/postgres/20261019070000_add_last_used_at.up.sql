@@ -0,0 +1,2 @@
+ALTER TABLE instances ADD COLUMN last_used_at TIMESTAMPTZ NULL;
+ALTER TABLE users ADD COLUMN last_used_at TIMESTAMPTZ NULL;
@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN software_detected_at TIMESTAMPTZ NULL;
@@ -0,0 +1 @@
+ALTER TABLE instances ADD COLUMN software TEXT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN ban_reason;
+ALTER TABLE users DROP COLUMN banned_at;
+ALTER TABLE instances DROP COLUMN ban_reason;
+ALTER TABLE instances DROP COLUMN banned_at;
@@ -0,0 +1,2 @@
+ALTER TABLE instances ADD COLUMN scopes TEXT NOT NULL DEFAULT 'read:search';
+ALTER TABLE users ADD COLUMN account TEXT NULL;
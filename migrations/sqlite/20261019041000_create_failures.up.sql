@@ -0,0 +1,12 @@
+CREATE TABLE "failures"
+(
+    id         INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    domain     TEXT    NOT NULL,
+    user_id    INTEGER NULL,
+    kind       TEXT    NOT NULL,
+    message    TEXT    NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT ( unixepoch() )
+) STRICT;
+
+CREATE INDEX failures_domain_idx ON failures (domain);
+CREATE INDEX failures_created_at_idx ON failures (created_at);
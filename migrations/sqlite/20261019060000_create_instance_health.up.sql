@@ -0,0 +1,11 @@
+CREATE TABLE "instance_health"
+(
+    id         INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    domain     TEXT    NOT NULL,
+    up         INTEGER NOT NULL,
+    message    TEXT    NULL,
+    created_at INTEGER NOT NULL DEFAULT ( unixepoch() )
+) STRICT;
+
+CREATE INDEX instance_health_domain_idx ON instance_health (domain);
+CREATE INDEX instance_health_created_at_idx ON instance_health (created_at);
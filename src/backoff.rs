@@ -0,0 +1,126 @@
+//! Tracking of the rate limits instances apply to each access token.
+//!
+//! Mastodon reports the remaining budget of a token in the `X-RateLimit-Remaining` and
+//! `X-RateLimit-Reset` headers. Once the budget is exhausted requests are delayed until the
+//! reset, or rejected if that is too far away, rather than being sent only to fail with a 429.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, StatusCode};
+use rocket::tokio::time::sleep;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime, UtcOffset};
+
+use crate::http::{HttpClient, InstanceResponse};
+use crate::FediurlError;
+
+const RATE_LIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const RATE_LIMIT_RESET: &str = "x-ratelimit-reset";
+/// Longest time a request will be held waiting for the budget to reset
+const MAX_DELAY: Duration = Duration::seconds(5);
+/// Number of tracked tokens above which expired budgets are discarded
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The remaining rate limit budget of access tokens
+#[derive(Default)]
+pub struct Backoff {
+    /// Budgets keyed by a hash of the access token, so that tokens aren't kept in memory
+    budgets: Mutex<HashMap<u64, Budget>>,
+}
+
+#[derive(Copy, Clone)]
+struct Budget {
+    remaining: u32,
+    reset: OffsetDateTime,
+}
+
+impl Backoff {
+    /// Send a request made with `access_token`, waiting for its budget to reset if necessary
+    ///
+    /// Returns `FediurlError::RateLimited` without sending the request if the budget won't reset
+    /// soon.
+    pub async fn send(
+        &self,
+        client: &HttpClient,
+        access_token: &str,
+        request: RequestBuilder,
+    ) -> Result<InstanceResponse, FediurlError> {
+        let key = token_key(access_token);
+        if let Some(budget) = self.exhausted(key) {
+            let delay = budget.reset - OffsetDateTime::now_utc();
+            if delay > MAX_DELAY {
+                return Err(FediurlError::RateLimited(Some(budget.reset)));
+            }
+            // The conversion fails if the reset has passed in the meantime
+            if let Ok(delay) = delay.try_into() {
+                sleep(delay).await;
+            }
+        }
+
+        let resp = client.send(request).await?;
+        self.update(key, &resp);
+        Ok(resp)
+    }
+
+    /// The budget for `key` if it is used up and yet to reset
+    fn exhausted(&self, key: u64) -> Option<Budget> {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let budgets = self.budgets.lock().unwrap();
+        budgets
+            .get(&key)
+            .filter(|budget| budget.remaining == 0 && budget.reset > OffsetDateTime::now_utc())
+            .copied()
+    }
+
+    fn update(&self, key: u64, resp: &InstanceResponse) {
+        let headers = resp.headers();
+        let remaining = if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            Some(0)
+        } else {
+            headers
+                .get(RATE_LIMIT_REMAINING)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        };
+        let (Some(remaining), Some(reset)) = (remaining, rate_limit_reset(headers)) else {
+            return;
+        };
+
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut budgets = self.budgets.lock().unwrap();
+        if budgets.len() > PRUNE_THRESHOLD {
+            let now = OffsetDateTime::now_utc();
+            budgets.retain(|_, budget| budget.reset > now);
+        }
+        budgets.insert(key, Budget { remaining, reset });
+    }
+}
+
+/// When the rate limit resets, from the `X-RateLimit-Reset` or `Retry-After` headers
+pub(crate) fn rate_limit_reset(headers: &HeaderMap) -> Option<OffsetDateTime> {
+    if let Some(reset) = headers
+        .get(RATE_LIMIT_RESET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| OffsetDateTime::parse(value, &Rfc3339).ok())
+    {
+        let reset = reset.to_offset(UtcOffset::UTC);
+        return Some(reset);
+    }
+
+    // Retry-After may also be an HTTP date but instances use the number of seconds
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(|seconds| OffsetDateTime::now_utc() + Duration::seconds(seconds))
+}
+
+fn token_key(access_token: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    access_token.hash(&mut hasher);
+    hasher.finish()
+}
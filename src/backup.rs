@@ -0,0 +1,343 @@
+//! Backups of the database, and exports of instances and users for moving between hosts.
+//!
+//! Backups are copies of the SQLite database made with `VACUUM INTO`, which is safe to run while
+//! the server is handling requests. Exports are JSON documents of the instances and their users.
+//! The OAuth client secrets and access tokens in an export are encrypted with an export key, which
+//! must be supplied again to import it.
+
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::Connection;
+use time::OffsetDateTime;
+
+use crate::db::DbConnection;
+use crate::models::instance::{Instance, InstanceId};
+use crate::models::user::{User, UserId};
+
+/// Version of the export format, increased when it changes incompatibly
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Environment variable holding the key used to encrypt and decrypt exports
+pub const EXPORT_KEY_VAR: &str = "FEDIURL_EXPORT_KEY";
+
+/// Length of the XChaCha20 nonce prefixed to each encrypted secret
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub enum BackupError {
+    Database(sqlx::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The backup file already exists
+    Exists(PathBuf),
+    /// Backups can only be made of SQLite databases
+    Unsupported,
+    /// The export key is malformed, or isn't the one the export was encrypted with
+    Key(&'static str),
+    /// The export is from an incompatible version or contains invalid values
+    Invalid(String),
+}
+
+/// A key for encrypting the secrets in an export
+pub struct ExportKey(XChaCha20Poly1305);
+
+/// An export of all instances and their users
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Export {
+    pub version: u32,
+    /// Unix time the export was made
+    pub exported_at: i64,
+    pub instances: Vec<ExportedInstance>,
+}
+
+/// An instance in an export, timestamps are Unix time
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedInstance {
+    pub domain: String,
+    pub client_id: String,
+    /// Encrypted with the export key
+    pub client_secret: String,
+    pub software: Option<String>,
+    #[serde(default)]
+    pub software_detected_at: Option<i64>,
+    pub search_api: Option<String>,
+    pub scopes: String,
+    pub banned_until: Option<i64>,
+    pub banned_at: Option<i64>,
+    pub ban_reason: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub last_used_at: Option<i64>,
+    pub users: Vec<ExportedUser>,
+}
+
+/// A user in an export, timestamps are Unix time
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedUser {
+    /// Encrypted with the export key
+    pub access_token: String,
+    pub account: Option<String>,
+    pub scopes: Option<String>,
+    pub banned_until: Option<i64>,
+    pub banned_at: Option<i64>,
+    pub ban_reason: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub last_used_at: Option<i64>,
+}
+
+/// What was imported from an export
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub instances: u64,
+    pub users: u64,
+    /// Domains of instances that were already present, they are left as is
+    pub skipped: Vec<String>,
+}
+
+/// Copy the database to a new file at `path`
+#[cfg(not(feature = "postgres"))]
+pub async fn backup(db: &mut DbConnection, path: &Path) -> Result<(), BackupError> {
+    if path.exists() {
+        return Err(BackupError::Exists(path.to_path_buf()));
+    }
+    let path = path
+        .to_str()
+        .ok_or_else(|| BackupError::Invalid(format!("{} is not UTF-8", path.display())))?;
+    sqlx::query("VACUUM INTO $1").bind(path).execute(db).await?;
+    Ok(())
+}
+
+/// PostgreSQL databases should be backed up with `pg_dump`
+#[cfg(feature = "postgres")]
+pub async fn backup(_db: &mut DbConnection, _path: &Path) -> Result<(), BackupError> {
+    Err(BackupError::Unsupported)
+}
+
+/// Name for a backup made at `time`, such as `fediurl-20230601T120000Z.db`
+pub fn backup_file_name(time: OffsetDateTime) -> String {
+    format!(
+        "fediurl-{:04}{:02}{:02}T{:02}{:02}{:02}Z.db",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+/// Export all instances and their users, encrypting their secrets with `key`
+pub async fn export(db: &mut DbConnection, key: &ExportKey) -> Result<Export, BackupError> {
+    let mut instances = Vec::new();
+    for instance in Instance::all(&mut *db).await? {
+        let users = User::for_instance(&mut *db, instance.id)
+            .await?
+            .into_iter()
+            .map(|user| ExportedUser {
+                access_token: key.encrypt(&user.access_token),
+                account: user.account,
+                scopes: user.scopes,
+                banned_until: user.banned_until.map(OffsetDateTime::unix_timestamp),
+                banned_at: user.banned_at.map(OffsetDateTime::unix_timestamp),
+                ban_reason: user.ban_reason,
+                created_at: user.created_at.unix_timestamp(),
+                updated_at: user.updated_at.unix_timestamp(),
+                last_used_at: user.last_used_at.map(OffsetDateTime::unix_timestamp),
+            })
+            .collect();
+        instances.push(ExportedInstance {
+            client_secret: key.encrypt(&instance.client_secret),
+            domain: instance.domain,
+            client_id: instance.client_id,
+            software: instance.software,
+            software_detected_at: instance
+                .software_detected_at
+                .map(OffsetDateTime::unix_timestamp),
+            search_api: instance.search_api,
+            scopes: instance.scopes,
+            banned_until: instance.banned_until.map(OffsetDateTime::unix_timestamp),
+            banned_at: instance.banned_at.map(OffsetDateTime::unix_timestamp),
+            ban_reason: instance.ban_reason,
+            created_at: instance.created_at.unix_timestamp(),
+            updated_at: instance.updated_at.unix_timestamp(),
+            last_used_at: instance.last_used_at.map(OffsetDateTime::unix_timestamp),
+            users,
+        });
+    }
+
+    Ok(Export {
+        version: EXPORT_VERSION,
+        exported_at: OffsetDateTime::now_utc().unix_timestamp(),
+        instances,
+    })
+}
+
+/// Import the instances and users in `export`, decrypting their secrets with `key`
+///
+/// Instances that are already present are skipped along with their users. Nothing is imported if
+/// any part of the export is invalid.
+pub async fn import(
+    db: &mut DbConnection,
+    key: &ExportKey,
+    export: Export,
+) -> Result<ImportSummary, BackupError> {
+    if export.version != EXPORT_VERSION {
+        return Err(BackupError::Invalid(format!(
+            "export version {} is not supported, expected {}",
+            export.version, EXPORT_VERSION
+        )));
+    }
+
+    let mut summary = ImportSummary::default();
+    let mut tx = db.begin().await?;
+    for exported in export.instances {
+        if Instance::from_domain_optional(&mut *tx, &exported.domain)
+            .await?
+            .is_some()
+        {
+            summary.skipped.push(exported.domain);
+            continue;
+        }
+
+        let instance = Instance {
+            id: InstanceId::from(0),
+            client_secret: key.decrypt(&exported.client_secret)?,
+            domain: exported.domain,
+            client_id: exported.client_id,
+            software: exported.software,
+            software_detected_at: from_optional_timestamp(exported.software_detected_at)?,
+            search_api: exported.search_api,
+            scopes: exported.scopes,
+            banned_until: from_optional_timestamp(exported.banned_until)?,
+            banned_at: from_optional_timestamp(exported.banned_at)?,
+            ban_reason: exported.ban_reason,
+            created_at: from_timestamp(exported.created_at)?,
+            updated_at: from_timestamp(exported.updated_at)?,
+            last_used_at: from_optional_timestamp(exported.last_used_at)?,
+        };
+        let instance_id = Instance::restore(&mut *tx, &instance).await?;
+        summary.instances += 1;
+
+        for exported in exported.users {
+            let user = User {
+                id: UserId::from(0),
+                instance_id,
+                access_token: key.decrypt(&exported.access_token)?,
+                account: exported.account,
+                scopes: exported.scopes,
+                banned_until: from_optional_timestamp(exported.banned_until)?,
+                banned_at: from_optional_timestamp(exported.banned_at)?,
+                ban_reason: exported.ban_reason,
+                created_at: from_timestamp(exported.created_at)?,
+                updated_at: from_timestamp(exported.updated_at)?,
+                last_used_at: from_optional_timestamp(exported.last_used_at)?,
+            };
+            User::restore(&mut *tx, &user).await?;
+            summary.users += 1;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(summary)
+}
+
+fn from_timestamp(timestamp: i64) -> Result<OffsetDateTime, BackupError> {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .map_err(|err| BackupError::Invalid(format!("invalid timestamp {}: {}", timestamp, err)))
+}
+
+fn from_optional_timestamp(timestamp: Option<i64>) -> Result<Option<OffsetDateTime>, BackupError> {
+    timestamp.map(from_timestamp).transpose()
+}
+
+impl ExportKey {
+    /// Generate a new random key, returned along with its base64 encoding
+    pub fn generate() -> (ExportKey, String) {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        (ExportKey(XChaCha20Poly1305::new(&key)), BASE64.encode(key))
+    }
+
+    /// Decode a key from its base64 encoding, as printed when it was generated
+    pub fn from_base64(encoded: &str) -> Result<ExportKey, BackupError> {
+        let key = BASE64
+            .decode(encoded.trim())
+            .map_err(|_| BackupError::Key("the export key is not valid base64"))?;
+        XChaCha20Poly1305::new_from_slice(&key)
+            .map(ExportKey)
+            .map_err(|_| BackupError::Key("the export key must be 32 bytes"))
+    }
+
+    /// Encrypt `plaintext`, returning the base64 encoded nonce and ciphertext
+    fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // NOTE(unwrap): encryption only fails if the plaintext is too large, which secrets aren't
+        let ciphertext = self.0.encrypt(&nonce, plaintext.as_bytes()).unwrap();
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        BASE64.encode(bytes)
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Result<String, BackupError> {
+        const WRONG_KEY: BackupError =
+            BackupError::Key("unable to decrypt the export, check the export key");
+
+        let bytes = BASE64.decode(encrypted).map_err(|_| WRONG_KEY)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(WRONG_KEY);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| WRONG_KEY)?;
+        String::from_utf8(plaintext).map_err(|_| WRONG_KEY)
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Database(err) => write!(f, "database error: {}", err),
+            BackupError::Io(err) => write!(f, "I/O error: {}", err),
+            BackupError::Json(err) => write!(f, "invalid export: {}", err),
+            BackupError::Exists(path) => write!(f, "{} already exists", path.display()),
+            BackupError::Unsupported => f.write_str(
+                "backups can only be made of SQLite databases, use pg_dump for PostgreSQL",
+            ),
+            BackupError::Key(message) => f.write_str(message),
+            BackupError::Invalid(message) => write!(f, "invalid export: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<sqlx::Error> for BackupError {
+    fn from(err: sqlx::Error) -> Self {
+        BackupError::Database(err)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        BackupError::Json(err)
+    }
+}
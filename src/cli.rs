@@ -0,0 +1,429 @@
+//! Command line interface to the `fediurl` binary
+//!
+//! With no arguments the web server is started. The remaining subcommands allow a deployment to
+//! be maintained from the shell, using the same configuration as the server.
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::process::ExitCode;
+
+use rocket::figment::Figment;
+use sqlx::Connection;
+use time::{Duration, OffsetDateTime};
+
+use crate::backup::{self, Export, ExportKey, EXPORT_KEY_VAR};
+use crate::config::AppConfig;
+#[cfg(not(feature = "postgres"))]
+use crate::config::SqliteConfig;
+#[cfg(not(feature = "postgres"))]
+use crate::db::SqliteSettings;
+use crate::db::{self, DbConnection, MIGRATOR};
+use crate::http::HttpClient;
+use crate::models::instance::Instance;
+use crate::models::user::User;
+use crate::policy::{BlockedDomains, InstancePolicy};
+use crate::web;
+use crate::web::session::revoke_token;
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+const USAGE: &str = "\
+Usage: fediurl [COMMAND]
+
+Commands:
+    serve                           Run the web server (default)
+    migrate                         Run pending database migrations
+    instances list                  List known instances
+    users prune --inactive AGE      Delete users that haven't been used in AGE (e.g. 180d) and
+                                    revoke their access tokens
+    ban DOMAIN [--days N] [--reason TEXT]
+                                    Ban an instance, permanently unless --days is given
+    unban DOMAIN                    Lift a ban on an instance
+    cache clear                     Forget the detected software and search API of instances
+    config check                    Check the configuration and database
+    backup PATH                     Copy the SQLite database to PATH while it's in use
+    export PATH                     Export instances and users as JSON, with secrets encrypted
+    import PATH                     Import instances and users from an export
+    help                            Show this message
+
+Configuration is read from Fediurl.toml and FEDIURL_ environment variables.
+
+The key that exports are encrypted with is read from FEDIURL_EXPORT_KEY. If it isn't set when
+exporting a new key is generated and printed, it must be set to import the export.";
+
+/// Run the command described by `args` (excluding the program name)
+pub async fn run(args: Vec<String>) -> ExitCode {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let res = match args.as_slice() {
+        [] | ["serve"] => serve().await,
+        ["migrate"] => migrate().await,
+        ["instances", "list"] => list_instances().await,
+        ["users", "prune", "--inactive", age] => prune_users(age).await,
+        ["ban", domain, options @ ..] => ban(domain, options).await,
+        ["unban", domain] => unban(domain).await,
+        ["cache", "clear"] => clear_cache().await,
+        ["config", "check"] => check_config().await,
+        ["backup", path] => backup(path).await,
+        ["export", path] => export(path).await,
+        ["import", path] => import(path).await,
+        ["help" | "-h" | "--help"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve() -> CliResult {
+    let _rocket = web::rocket().launch().await?;
+    Ok(())
+}
+
+async fn migrate() -> CliResult {
+    let mut db = connect(&web::figment()).await?;
+    MIGRATOR.run(&mut db).await?;
+    println!("Migrations complete");
+    Ok(())
+}
+
+async fn list_instances() -> CliResult {
+    let mut db = connect(&web::figment()).await?;
+    let instances = Instance::summaries(&mut db).await?;
+    println!(
+        "{:<40} {:<12} {:>6} {:>8} {:<10}",
+        "DOMAIN", "SOFTWARE", "USERS", "FAILURES", "BANNED"
+    );
+    for instance in instances {
+        let banned = match instance.active_ban() {
+            Some(_) => "yes",
+            None => "",
+        };
+        println!(
+            "{:<40} {:<12} {:>6} {:>8} {:<10}",
+            instance.domain,
+            instance.software.as_deref().unwrap_or("-"),
+            instance.user_count,
+            instance.failure_count,
+            banned
+        );
+    }
+    Ok(())
+}
+
+async fn prune_users(age: &str) -> CliResult {
+    let age = parse_age(age)
+        .ok_or_else(|| format!("invalid age '{}', expected a positive age e.g. 180d", age))?;
+    let cutoff = OffsetDateTime::now_utc()
+        .checked_sub(age)
+        .ok_or_else(|| format!("age '{}' is too long", age))?;
+    let figment = web::figment();
+    let config = figment.extract::<AppConfig>()?;
+    let client = HttpClient::new(&config.http, None)?;
+    let mut db = connect(&figment).await?;
+
+    let users = User::inactive(&mut db, cutoff).await?;
+    let (mut revoked, mut unrevoked) = (0, 0);
+    let mut instance: Option<Instance> = None;
+    // Users are ordered by instance, so each instance is only loaded once
+    for user in &users {
+        if instance.as_ref().map(|instance| instance.id) != Some(user.instance_id) {
+            instance = Some(user.instance(&mut db).await?);
+        }
+        // NOTE(unwrap): the instance of the user was loaded above
+        let instance = instance.as_ref().unwrap();
+
+        // The user is deleted even if the token can't be revoked, as the instance may be gone
+        match revoke_token(&client, instance, &user.access_token).await {
+            Ok(true) => revoked += 1,
+            Ok(false) => unrevoked += 1,
+            Err(err) => {
+                eprintln!(
+                    "Unable to revoke token of user {} on {}: {}",
+                    user.id, instance.domain, err
+                );
+                unrevoked += 1;
+            }
+        }
+        User::delete(&mut db, user.id).await?;
+    }
+
+    println!(
+        "Pruned {} user(s), revoked {} token(s), {} token(s) couldn't be revoked",
+        users.len(),
+        revoked,
+        unrevoked
+    );
+    Ok(())
+}
+
+async fn ban(domain: &str, options: &[&str]) -> CliResult {
+    let mut days = None;
+    let mut reason = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (*option, options.next()) {
+            ("--days", Some(value)) => {
+                let value = value
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid number of days '{}'", value))?;
+                days = Some(value)
+            }
+            ("--reason", Some(value)) => reason = Some(*value),
+            _ => return Err(format!("unexpected argument '{}'\n\n{}", option, USAGE).into()),
+        }
+    }
+
+    let mut db = connect(&web::figment()).await?;
+    let instance = find_instance(&mut db, domain).await?;
+    let until = days.map(|days| OffsetDateTime::now_utc() + Duration::days(days.into()));
+    Instance::ban(&mut db, instance.id, until, reason).await?;
+    println!("Banned {}", instance.domain);
+    Ok(())
+}
+
+async fn unban(domain: &str) -> CliResult {
+    let mut db = connect(&web::figment()).await?;
+    let instance = find_instance(&mut db, domain).await?;
+    Instance::unban(&mut db, instance.id).await?;
+    println!("Unbanned {}", instance.domain);
+    Ok(())
+}
+
+async fn clear_cache() -> CliResult {
+    let mut db = connect(&web::figment()).await?;
+    let cleared = Instance::clear_detected(&mut db).await?;
+    println!("Cleared detected software of {} instance(s)", cleared);
+    Ok(())
+}
+
+async fn backup(path: &str) -> CliResult {
+    let mut db = connect(&web::figment()).await?;
+    backup::backup(&mut db, Path::new(path)).await?;
+    println!("Backed up database to {}", path);
+    Ok(())
+}
+
+async fn export(path: &str) -> CliResult {
+    let key = match env::var(EXPORT_KEY_VAR) {
+        Ok(key) => ExportKey::from_base64(&key)?,
+        Err(_) => {
+            let (key, encoded) = ExportKey::generate();
+            println!(
+                "Generated export key, set {} to this to import:",
+                EXPORT_KEY_VAR
+            );
+            println!("{}", encoded);
+            key
+        }
+    };
+
+    let mut db = connect(&web::figment()).await?;
+    let export = backup::export(&mut db, &key).await?;
+    let users = export
+        .instances
+        .iter()
+        .map(|instance| instance.users.len())
+        .sum::<usize>();
+    let file = File::options().write(true).create_new(true).open(path)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &export)?;
+    println!(
+        "Exported {} instance(s) and {} user(s) to {}",
+        export.instances.len(),
+        users,
+        path
+    );
+    Ok(())
+}
+
+async fn import(path: &str) -> CliResult {
+    let key = env::var(EXPORT_KEY_VAR)
+        .map_err(|_| format!("{} must be set to the key of the export", EXPORT_KEY_VAR))?;
+    let key = ExportKey::from_base64(&key)?;
+    let file = BufReader::new(File::open(path)?);
+    let export: Export = serde_json::from_reader(file)?;
+
+    let mut db = connect(&web::figment()).await?;
+    let summary = backup::import(&mut db, &key, export).await?;
+    println!(
+        "Imported {} instance(s) and {} user(s)",
+        summary.instances, summary.users
+    );
+    for domain in summary.skipped {
+        println!("Skipped {}, it's already present", domain);
+    }
+    Ok(())
+}
+
+/// Check the configuration, reporting all problems found
+async fn check_config() -> CliResult {
+    let figment = web::figment();
+    let mut problems = 0;
+
+    match figment.extract::<AppConfig>() {
+        Ok(config) => {
+            if config.hosts.is_empty() {
+                println!("✗ hosts is empty, at least one host is required");
+                problems += 1;
+            } else {
+                println!("✓ app config is valid");
+            }
+            let policy = InstancePolicy::load(&config.instance_policy)
+                .and_then(|_| BlockedDomains::load(&config));
+            match policy {
+                Ok(_) => println!("✓ instance policy loaded"),
+                Err(err) => {
+                    println!("✗ unable to load instance policy: {}", err);
+                    problems += 1;
+                }
+            }
+        }
+        Err(err) => {
+            println!("✗ app config is invalid: {}", err);
+            problems += 1;
+        }
+    }
+
+    match rocket::Config::try_from(&figment) {
+        Ok(config) if config.secret_key.is_zero() => {
+            println!("✗ secret_key is not set, sessions will not persist across restarts");
+            problems += 1;
+        }
+        Ok(_) => println!("✓ rocket config is valid"),
+        Err(err) => {
+            println!("✗ rocket config is invalid: {}", err);
+            problems += 1;
+        }
+    }
+
+    match connect(&figment).await {
+        Ok(mut db) => {
+            println!("✓ connected to database");
+            #[cfg(not(feature = "postgres"))]
+            {
+                problems += check_sqlite_settings(&figment, &mut db).await;
+            }
+            match db::pending_migrations(&mut db).await {
+                Ok(0) => println!("✓ database is up to date"),
+                Ok(pending) => {
+                    println!(
+                        "✗ {} pending migration(s), run `fediurl migrate` to apply them",
+                        pending
+                    );
+                    problems += 1;
+                }
+                Err(err) => {
+                    println!("✗ unable to check for pending migrations: {}", err);
+                    problems += 1;
+                }
+            }
+        }
+        Err(err) => {
+            println!("✗ unable to connect to database: {}", err);
+            problems += 1;
+        }
+    }
+
+    if problems == 0 {
+        Ok(())
+    } else {
+        Err(format!("{} problem(s) found", problems).into())
+    }
+}
+
+/// Print the settings in effect on the database connection, returning the number of problems
+#[cfg(not(feature = "postgres"))]
+async fn check_sqlite_settings(figment: &Figment, db: &mut DbConnection) -> u32 {
+    let settings = match SqliteSettings::query(db).await {
+        Ok(settings) => settings,
+        Err(err) => {
+            println!("✗ unable to query database settings: {}", err);
+            return 1;
+        }
+    };
+    println!("  {}", settings);
+
+    let mut problems = 0;
+    if let Ok(config) = figment.extract_inner::<SqliteConfig>("databases.fediurl_db") {
+        for difference in settings.differences(&config) {
+            println!("✗ {}", difference);
+            problems += 1;
+        }
+    }
+    problems
+}
+
+/// Open a connection to the database configured for the server
+async fn connect(figment: &Figment) -> Result<DbConnection, Box<dyn Error>> {
+    let url = figment
+        .extract_inner::<String>("databases.fediurl_db.url")
+        .map_err(|err| format!("database url is not configured: {}", err))?;
+    #[cfg(not(feature = "postgres"))]
+    let options = {
+        let config = figment
+            .extract_inner::<SqliteConfig>("databases.fediurl_db")
+            .map_err(|err| format!("database config is invalid: {}", err))?;
+        db::connect_options(&url, &config)?
+    };
+    #[cfg(feature = "postgres")]
+    let options = db::connect_options(&url)?;
+    Ok(DbConnection::connect_with(&options).await?)
+}
+
+async fn find_instance(db: &mut DbConnection, domain: &str) -> Result<Instance, String> {
+    match Instance::from_domain_optional(db, domain).await {
+        Ok(Some(instance)) => Ok(instance),
+        Ok(None) => Err(format!("unknown instance '{}'", domain)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parse an age such as `12h`, `180d` or `4w`
+///
+/// Ages must be positive, a cutoff of now or later would include every user.
+fn parse_age(age: &str) -> Option<Duration> {
+    let unit = age.chars().last()?;
+    let value = age[..age.len() - unit.len_utf8()].parse::<u32>().ok()?;
+    if value == 0 {
+        return None;
+    }
+    let value = i64::from(value);
+    match unit {
+        'h' => Some(Duration::hours(value)),
+        'd' => Some(Duration::days(value)),
+        'w' => Some(Duration::weeks(value)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ages() {
+        assert_eq!(parse_age("12h"), Some(Duration::hours(12)));
+        assert_eq!(parse_age("180d"), Some(Duration::days(180)));
+        assert_eq!(parse_age("4w"), Some(Duration::weeks(4)));
+    }
+
+    #[test]
+    fn parse_invalid_ages() {
+        for age in ["", "180", "d", "180y", "1.5d", "0d", "0h", "-1d", "-4w"] {
+            assert_eq!(parse_age(age), None, "{:?}", age);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::env;
+use std::path::PathBuf;
 
 use rocket::http::uri::Host;
 use rocket::serde::{Deserialize, Serialize};
@@ -7,7 +8,110 @@ use rocket::serde::{Deserialize, Serialize};
 #[serde(crate = "rocket::serde")]
 pub struct AppConfig {
     pub hosts: Vec<Host<'static>>,
+    /// Errors are reported to Sentry when built with the `sentry` feature
     pub sentry_dsn: Option<String>,
+    /// Traces are exported when built with the `otel` feature
+    pub otel: OtelConfig,
+    /// Accounts allowed to access the admin interface, in `user@domain` form
+    pub admins: Vec<String>,
+    /// Restrictions on the instances that can use Fediurl
+    pub instance_policy: InstancePolicyConfig,
+    /// Remote domains that content won't be resolved from
+    pub blocked_domains: Vec<String>,
+    /// File of blocked remote domains, in the same formats as `instance_policy.deny_file`
+    pub blocked_domains_file: Option<PathBuf>,
+    pub rate_limits: RateLimitConfig,
+    /// Settings for requests to instances
+    pub http: HttpConfig,
+    pub metrics: MetricsConfig,
+    /// Directory that backups made from the admin interface are written to
+    pub backup_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct InstancePolicyConfig {
+    /// When set, only these instances (and those in `allow_file`) may use Fediurl
+    pub allow: Option<Vec<String>>,
+    pub allow_file: Option<PathBuf>,
+    /// Instances that may not use Fediurl
+    pub deny: Vec<String>,
+    /// File of denied instances, either one per line or a Mastodon domain block CSV export
+    pub deny_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct RateLimitConfig {
+    /// Rewrites by each user
+    pub user: RateLimit,
+    /// Rewrites by all users of an instance
+    pub instance: RateLimit,
+    /// Log in attempts from each client IP
+    pub login: RateLimit,
+}
+
+/// A token bucket rate limit, disabled if either value is zero
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RateLimit {
+    /// Number of requests that can be made in quick succession
+    pub burst: u32,
+    /// Number of requests allowed per minute once the burst is used up
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct HttpConfig {
+    /// Seconds to wait for a connection to be established
+    pub connect_timeout: u64,
+    /// Seconds to wait for the response to a request to start
+    pub read_timeout: u64,
+    /// Seconds allowed for a request to complete, including reading the response body
+    pub timeout: u64,
+    /// Number of times failed GET requests are retried
+    pub retries: u32,
+    /// URL of a proxy to send requests through
+    pub proxy: Option<String>,
+    /// Contact details (URL or email address) for instance admins, included in the user agent
+    pub contact: Option<String>,
+    /// Consecutive failures after which an instance is considered down, 0 to disable
+    pub circuit_failures: u32,
+    /// Seconds to wait before trying an instance that is considered down again
+    pub circuit_cooldown: u64,
+}
+
+/// SQLite connection settings, read from the `databases.fediurl_db` table along with the url
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SqliteConfig {
+    /// `wal`, `delete`, `truncate`, `persist`, `memory` or `off`
+    pub journal_mode: String,
+    /// `off`, `normal`, `full` or `extra`, `normal` is safe when the journal mode is WAL
+    pub synchronous: String,
+    /// Seconds to wait for other connections to release a lock on the database
+    pub busy_timeout: u64,
+    /// Whether foreign key constraints are enforced
+    pub foreign_keys: bool,
+}
+
+/// Export of traces to an OpenTelemetry collector
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct OtelConfig {
+    /// OTLP gRPC endpoint, such as `http://localhost:4317`
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+/// The Prometheus `/metrics` endpoint
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// When set, requests must have an `Authorization: Bearer <token>` header
+    pub token: Option<String>,
 }
 
 impl Default for AppConfig {
@@ -15,10 +119,82 @@ impl Default for AppConfig {
         AppConfig {
             hosts: Vec::new(),
             sentry_dsn: None,
+            otel: OtelConfig::default(),
+            admins: Vec::new(),
+            instance_policy: InstancePolicyConfig::default(),
+            blocked_domains: Vec::new(),
+            blocked_domains_file: None,
+            rate_limits: RateLimitConfig::default(),
+            http: HttpConfig::default(),
+            metrics: MetricsConfig::default(),
+            backup_dir: None,
         }
     }
 }
 
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            user: RateLimit {
+                burst: 20,
+                per_minute: 30,
+            },
+            instance: RateLimit {
+                burst: 100,
+                per_minute: 300,
+            },
+            login: RateLimit {
+                burst: 10,
+                per_minute: 5,
+            },
+        }
+    }
+}
+
+impl Default for SqliteConfig {
+    fn default() -> SqliteConfig {
+        SqliteConfig {
+            journal_mode: String::from("wal"),
+            synchronous: String::from("normal"),
+            busy_timeout: 5,
+            foreign_keys: true,
+        }
+    }
+}
+
+impl Default for OtelConfig {
+    fn default() -> OtelConfig {
+        OtelConfig {
+            endpoint: None,
+            service_name: crate::NAME.to_lowercase(),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            connect_timeout: 5,
+            read_timeout: 10,
+            timeout: 30,
+            retries: 2,
+            proxy: None,
+            contact: None,
+            circuit_failures: 5,
+            circuit_cooldown: 60,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Whether `account` (in `user@domain` form) is an admin
+    pub fn is_admin(&self, account: &str) -> bool {
+        self.admins
+            .iter()
+            .any(|admin| admin.trim_start_matches('@').eq_ignore_ascii_case(account))
+    }
+}
+
 pub fn git_revision() -> String {
     env::var("FEDIURL_REVISION").unwrap_or_else(|_| String::from("dev"))
 }
@@ -1,5 +1,253 @@
-use rocket_db_pools::{sqlx, Database};
+#[cfg(not(feature = "postgres"))]
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rocket::figment::Figment;
+use rocket_db_pools::{sqlx, Database, Error};
+use sqlx::migrate::Migrator;
+use sqlx::pool::{PoolConnection, PoolOptions};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::PgConnectOptions;
+#[cfg(not(feature = "postgres"))]
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::ConnectOptions;
+use time::OffsetDateTime;
+
+#[cfg(not(feature = "postgres"))]
+use crate::config::SqliteConfig;
+
+/// The database Fediurl is built for, PostgreSQL with the `postgres` feature and SQLite otherwise
+#[cfg(not(feature = "postgres"))]
+pub type Backend = sqlx::Sqlite;
+#[cfg(feature = "postgres")]
+pub type Backend = sqlx::Postgres;
+
+pub type DbConnection = <Backend as sqlx::Database>::Connection;
+pub type DbPool = sqlx::Pool<Backend>;
+
+/// Timestamps as they are bound to queries: Unix time in SQLite, `TIMESTAMPTZ` in PostgreSQL
+#[cfg(not(feature = "postgres"))]
+pub type Timestamp = i64;
+#[cfg(feature = "postgres")]
+pub type Timestamp = OffsetDateTime;
 
 #[derive(Database)]
 #[database("fediurl_db")]
-pub struct Db(pub sqlx::SqlitePool);
+pub struct Db(pub Pool);
+
+/// The database migrations, embedded at build time
+#[cfg(not(feature = "postgres"))]
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+#[cfg(feature = "postgres")]
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// A connection pool with the connection options from the config applied
+///
+/// The options are read from the `databases.fediurl_db` table of the config, alongside those of
+/// `rocket_db_pools`. For SQLite these include the settings in `SqliteConfig`.
+pub struct Pool(DbPool);
+
+/// The settings in effect on a connection, as reported by SQLite
+#[cfg(not(feature = "postgres"))]
+pub struct SqliteSettings {
+    pub journal_mode: String,
+    pub synchronous: &'static str,
+    /// Milliseconds
+    pub busy_timeout: i64,
+    pub foreign_keys: bool,
+}
+
+impl Db {
+    /// The underlying SQLx pool
+    pub fn pool(&self) -> &DbPool {
+        &self.0 .0
+    }
+}
+
+#[rocket::async_trait]
+impl rocket_db_pools::Pool for Pool {
+    type Connection = PoolConnection<Backend>;
+    type Error = Error<sqlx::Error>;
+
+    async fn init(figment: &Figment) -> Result<Self, Self::Error> {
+        let config = figment
+            .extract::<rocket_db_pools::Config>()
+            .map_err(Error::Config)?;
+        #[cfg(not(feature = "postgres"))]
+        let options = {
+            let sqlite = figment.extract::<SqliteConfig>().map_err(Error::Config)?;
+            connect_options(&config.url, &sqlite)
+        };
+        #[cfg(feature = "postgres")]
+        let options = connect_options(&config.url);
+        let options = options.map_err(Error::Init)?;
+
+        // The same pool options as rocket_db_pools uses for its own pools
+        let pool = PoolOptions::new()
+            .max_connections(config.max_connections as u32)
+            .acquire_timeout(Duration::from_secs(config.connect_timeout))
+            .idle_timeout(config.idle_timeout.map(Duration::from_secs))
+            .min_connections(config.min_connections.unwrap_or_default())
+            .connect_with(options)
+            .await
+            .map_err(Error::Init)?;
+        Ok(Pool(pool))
+    }
+
+    async fn get(&self) -> Result<Self::Connection, Self::Error> {
+        self.0.acquire().await.map_err(Error::Get)
+    }
+
+    async fn close(&self) {
+        self.0.close().await;
+    }
+}
+
+/// The options for connecting to the database at `url`
+#[cfg(not(feature = "postgres"))]
+pub fn connect_options(
+    url: &str,
+    config: &SqliteConfig,
+) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let mut options = SqliteConnectOptions::from_str(url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::from_str(&config.journal_mode)?)
+        .synchronous(SqliteSynchronous::from_str(&config.synchronous)?)
+        .busy_timeout(Duration::from_secs(config.busy_timeout))
+        .foreign_keys(config.foreign_keys);
+    options.disable_statement_logging();
+    Ok(options)
+}
+
+/// The options for connecting to the database at `url`
+#[cfg(feature = "postgres")]
+pub fn connect_options(url: &str) -> Result<PgConnectOptions, sqlx::Error> {
+    let mut options = PgConnectOptions::from_str(url)?;
+    options.disable_statement_logging();
+    Ok(options)
+}
+
+/// Convert `time` to the type that timestamp columns are bound as
+#[cfg(not(feature = "postgres"))]
+pub fn timestamp(time: OffsetDateTime) -> Timestamp {
+    time.unix_timestamp()
+}
+
+/// Convert `time` to the type that timestamp columns are bound as
+#[cfg(feature = "postgres")]
+pub fn timestamp(time: OffsetDateTime) -> Timestamp {
+    time
+}
+
+/// Query for whether the table that sqlx records applied migrations in exists
+#[cfg(not(feature = "postgres"))]
+const MIGRATIONS_TABLE_EXISTS: &str =
+    "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'";
+#[cfg(feature = "postgres")]
+const MIGRATIONS_TABLE_EXISTS: &str = "SELECT to_regclass('_sqlx_migrations') IS NOT NULL";
+
+/// Count the migrations that have not been applied to the database
+pub async fn pending_migrations(db: &mut DbConnection) -> Result<usize, sqlx::Error> {
+    // The migrations table won't exist if no migrations have been run
+    let exists = sqlx::query_scalar::<_, bool>(MIGRATIONS_TABLE_EXISTS)
+        .fetch_one(&mut *db)
+        .await?;
+    let applied = if exists {
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations")
+            .fetch_all(&mut *db)
+            .await?
+    } else {
+        Vec::new()
+    };
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .count())
+}
+
+#[cfg(not(feature = "postgres"))]
+impl SqliteSettings {
+    pub async fn query(db: &mut DbConnection) -> Result<SqliteSettings, sqlx::Error> {
+        let journal_mode = sqlx::query_scalar::<_, String>("PRAGMA journal_mode")
+            .fetch_one(&mut *db)
+            .await?;
+        let synchronous = sqlx::query_scalar::<_, i64>("PRAGMA synchronous")
+            .fetch_one(&mut *db)
+            .await?;
+        let busy_timeout = sqlx::query_scalar::<_, i64>("PRAGMA busy_timeout")
+            .fetch_one(&mut *db)
+            .await?;
+        let foreign_keys = sqlx::query_scalar::<_, bool>("PRAGMA foreign_keys")
+            .fetch_one(&mut *db)
+            .await?;
+
+        Ok(SqliteSettings {
+            journal_mode: journal_mode.to_lowercase(),
+            synchronous: match synchronous {
+                0 => "off",
+                1 => "normal",
+                2 => "full",
+                3 => "extra",
+                _ => "unknown",
+            },
+            busy_timeout,
+            foreign_keys,
+        })
+    }
+
+    /// Descriptions of the settings that differ from those configured
+    pub fn differences(&self, config: &SqliteConfig) -> Vec<String> {
+        let mut differences = Vec::new();
+        if !self.journal_mode.eq_ignore_ascii_case(&config.journal_mode) {
+            differences.push(format!(
+                "journal_mode is {} rather than {}",
+                self.journal_mode, config.journal_mode
+            ));
+        }
+        if !self.synchronous.eq_ignore_ascii_case(&config.synchronous) {
+            differences.push(format!(
+                "synchronous is {} rather than {}",
+                self.synchronous, config.synchronous
+            ));
+        }
+        if self.busy_timeout != config.busy_timeout as i64 * 1000 {
+            differences.push(format!(
+                "busy_timeout is {}ms rather than {}s",
+                self.busy_timeout, config.busy_timeout
+            ));
+        }
+        if self.foreign_keys != config.foreign_keys {
+            differences.push(format!(
+                "foreign_keys is {} rather than {}",
+                on_off(self.foreign_keys),
+                on_off(config.foreign_keys)
+            ));
+        }
+        differences
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+impl fmt::Display for SqliteSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "journal_mode={} synchronous={} busy_timeout={}ms foreign_keys={}",
+            self.journal_mode,
+            self.synchronous,
+            self.busy_timeout,
+            on_off(self.foreign_keys)
+        )
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
@@ -0,0 +1,230 @@
+//! The HTTP client used for all requests to instances.
+
+pub mod circuit;
+
+use std::fmt;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use reqwest::{Client, Method, Proxy, Request, RequestBuilder, Response, StatusCode};
+use rocket::serde::Serialize;
+use rocket::tokio::time::{sleep, timeout};
+
+use crate::config::HttpConfig;
+use crate::db::DbPool;
+use crate::http::circuit::{CircuitBreaker, InstanceStatus};
+use crate::report::UpstreamSpan;
+use crate::FediurlError;
+
+/// Delay before the first retry, doubled for each subsequent retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// A shared HTTP client, so that connections to instances are reused
+///
+/// Requests should be sent with `HttpClient::send`, which applies the read timeout and retries
+/// GET requests that fail in a way that may be temporary.
+pub struct HttpClient {
+    client: Client,
+    read_timeout: Duration,
+    retries: u32,
+    breaker: CircuitBreaker,
+}
+
+impl HttpClient {
+    /// Build the client, `db` is used to record the health of instances
+    pub fn new(config: &HttpConfig, db: Option<DbPool>) -> reqwest::Result<HttpClient> {
+        let user_agent = match config.contact.as_deref() {
+            Some(contact) => format!(
+                "{}/{} (+{})",
+                crate::NAME,
+                env!("CARGO_PKG_VERSION"),
+                contact
+            ),
+            None => format!("{}/{}", crate::NAME, env!("CARGO_PKG_VERSION")),
+        };
+
+        let mut builder = Client::builder()
+            .user_agent(user_agent)
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .timeout(Duration::from_secs(config.timeout));
+        if let Some(proxy) = config.proxy.as_deref() {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            read_timeout: Duration::from_secs(config.read_timeout),
+            retries: config.retries,
+            breaker: CircuitBreaker::new(
+                config.circuit_failures,
+                time::Duration::seconds(config.circuit_cooldown as i64),
+                db,
+            ),
+        })
+    }
+
+    pub fn get<U: reqwest::IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post<U: reqwest::IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// The availability of the instance at `domain`
+    pub fn status(&self, domain: &str) -> InstanceStatus {
+        self.breaker.status(domain)
+    }
+
+    /// Send a request, failing immediately if the instance is considered down
+    ///
+    /// Errors from sending the request carry the details of the request.
+    pub async fn send(&self, request: RequestBuilder) -> Result<InstanceResponse, FediurlError> {
+        let request = request.build()?;
+        let mut context = RequestContext::new(&request);
+        let domain = context.domain.clone();
+        self.breaker.check(&domain)?;
+
+        let span = UpstreamSpan::start(&context);
+        let start = Instant::now();
+        let res = self.send_with_retries(request).await;
+        context.elapsed_ms = start.elapsed().as_millis() as u64;
+        match &res {
+            Ok(resp) if is_temporary(resp.status()) => {
+                let message = format!("{} response", resp.status());
+                self.breaker.record_failure(&domain, &message)
+            }
+            Err(err) if is_unavailable(err) => {
+                self.breaker.record_failure(&domain, &err.to_string())
+            }
+            Ok(_) => self.breaker.record_success(&domain),
+            // Other errors, such as error responses, show that the instance is up
+            Err(_) => self.breaker.record_success(&domain),
+        }
+
+        match res {
+            Ok(response) => {
+                context.status = Some(response.status().as_u16());
+                span.end(&context, None);
+                Ok(InstanceResponse { response, context })
+            }
+            Err(err) => {
+                span.end(&context, Some(&err));
+                Err(err.with_context(context))
+            }
+        }
+    }
+
+    /// Send a request, retrying GET requests with a jittered backoff on connection errors,
+    /// timeouts, and gateway errors
+    async fn send_with_retries(&self, request: Request) -> Result<Response, FediurlError> {
+        if request.method() != Method::GET {
+            return self.execute(request).await;
+        }
+
+        let mut attempt = 0;
+        loop {
+            // NOTE(unwrap): GET requests have no body so can always be cloned
+            let res = self.execute(request.try_clone().unwrap()).await;
+            let retry = match &res {
+                Ok(resp) => is_temporary(resp.status()),
+                Err(err) => is_unavailable(err),
+            };
+            if !retry || attempt >= self.retries {
+                return res;
+            }
+
+            attempt += 1;
+            let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+            let jitter = rand::thread_rng().gen_range(0.5..1.5);
+            debug!("retrying {} in {:?}", request.url(), delay.mul_f64(jitter));
+            sleep(delay.mul_f64(jitter)).await;
+        }
+    }
+
+    /// Execute a request, failing if the response headers aren't received within the read timeout
+    async fn execute(&self, request: Request) -> Result<Response, FediurlError> {
+        match timeout(self.read_timeout, self.client.execute(request)).await {
+            Ok(res) => Ok(res?),
+            Err(_elapsed) => Err(FediurlError::Timeout),
+        }
+    }
+}
+
+/// The details of a request to an instance, for tracing errors back to it
+#[derive(Clone, Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RequestContext {
+    pub method: String,
+    pub domain: String,
+    /// The path of the URL, without the query which may contain user data
+    pub endpoint: String,
+    /// The status of the response, if one was received
+    pub status: Option<u16>,
+    /// Time taken to get the response, including retries
+    pub elapsed_ms: u64,
+}
+
+impl RequestContext {
+    fn new(request: &Request) -> Self {
+        RequestContext {
+            method: request.method().to_string(),
+            domain: request.url().host_str().unwrap_or_default().to_string(),
+            endpoint: request.url().path().to_string(),
+            status: None,
+            elapsed_ms: 0,
+        }
+    }
+}
+
+impl fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}{}", self.method, self.domain, self.endpoint)?;
+        if let Some(status) = self.status {
+            write!(f, " returned {}", status)?;
+        }
+        write!(f, " after {}ms", self.elapsed_ms)
+    }
+}
+
+/// A response from an instance, along with the details of the request
+pub struct InstanceResponse {
+    response: Response,
+    context: RequestContext,
+}
+
+impl InstanceResponse {
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
+
+    pub fn into_parts(self) -> (Response, RequestContext) {
+        (self.response, self.context)
+    }
+}
+
+impl Deref for InstanceResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Self::Target {
+        &self.response
+    }
+}
+
+/// Whether an error indicates that the instance could not be reached
+fn is_unavailable(err: &FediurlError) -> bool {
+    match err {
+        FediurlError::Timeout => true,
+        FediurlError::Http(err) => err.is_connect() || err.is_timeout(),
+        _ => false,
+    }
+}
+
+/// Whether a response status indicates an error that may be resolved by trying again
+fn is_temporary(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
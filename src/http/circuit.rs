@@ -0,0 +1,155 @@
+//! Per-instance circuit breaker, so that requests to an instance that is down fail fast.
+//!
+//! After a number of consecutive failures the circuit for the instance is opened and requests
+//! are refused until the cooldown has passed. A single request is then let through to test the
+//! instance, closing the circuit again if it succeeds. Instances going down and coming back up
+//! are recorded in the `instance_health` table.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rocket::tokio;
+use time::{Duration, OffsetDateTime};
+
+use crate::db::DbPool;
+use crate::models::health::HealthEvent;
+use crate::FediurlError;
+
+pub struct CircuitBreaker {
+    /// Consecutive failures after which the circuit is opened, 0 to disable
+    failure_threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+    /// Used to record health events, if available
+    db: Option<DbPool>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Circuit {
+    failures: u32,
+    /// When the next request to test the instance may be made, if the circuit is open
+    open_until: Option<OffsetDateTime>,
+    down_since: Option<OffsetDateTime>,
+}
+
+/// The availability of an instance, as seen by Fediurl
+#[derive(Copy, Clone, Debug)]
+pub enum InstanceStatus {
+    Up,
+    /// Recent requests have failed but not enough to consider the instance down
+    Degraded {
+        failures: u32,
+    },
+    Down {
+        since: OffsetDateTime,
+        retry_at: OffsetDateTime,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration, db: Option<DbPool>) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            circuits: Mutex::default(),
+            db,
+        }
+    }
+
+    /// Check whether a request to `domain` may be made
+    pub fn check(&self, domain: &str) -> Result<(), FediurlError> {
+        let now = OffsetDateTime::now_utc();
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(domain) else {
+            return Ok(());
+        };
+
+        match circuit.open_until {
+            Some(until) if until > now => Err(FediurlError::InstanceUnavailable {
+                domain: domain.to_string(),
+                retry_at: until,
+            }),
+            Some(_) => {
+                // Let this request through to test the instance, others continue to fail until
+                // it completes.
+                circuit.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self, domain: &str) {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.remove(domain) {
+            if circuit.down_since.is_some() {
+                info!("{} is back up", domain);
+                self.record_event(domain, true, None);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, domain: &str, message: &str) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(domain.to_string()).or_default();
+        circuit.failures += 1;
+        if circuit.failures >= self.failure_threshold {
+            circuit.open_until = Some(now + self.cooldown);
+            if circuit.down_since.is_none() {
+                circuit.down_since = Some(now);
+                warn!("{} appears to be down: {}", domain, message);
+                self.record_event(domain, false, Some(message.to_string()));
+            }
+        }
+    }
+
+    pub fn status(&self, domain: &str) -> InstanceStatus {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let circuits = self.circuits.lock().unwrap();
+        match circuits.get(domain) {
+            Some(Circuit {
+                down_since: Some(since),
+                open_until,
+                ..
+            }) => InstanceStatus::Down {
+                since: *since,
+                retry_at: open_until.unwrap_or(*since),
+            },
+            Some(circuit) => InstanceStatus::Degraded {
+                failures: circuit.failures,
+            },
+            None => InstanceStatus::Up,
+        }
+    }
+
+    /// Record a health event in the background, so that requests aren't held up
+    fn record_event(&self, domain: &str, up: bool, message: Option<String>) {
+        let Some(pool) = self.db.clone() else {
+            return;
+        };
+        let domain = domain.to_string();
+        tokio::spawn(async move {
+            let res = match pool.acquire().await {
+                Ok(mut db) => HealthEvent::create(&mut *db, &domain, up, message.as_deref()).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                warn!("unable to record health of {}: {}", domain, err);
+            }
+        });
+    }
+}
+
+impl InstanceStatus {
+    pub fn is_down(&self) -> bool {
+        matches!(self, InstanceStatus::Down { .. })
+    }
+}
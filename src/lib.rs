@@ -3,17 +3,32 @@ extern crate rocket;
 
 use std::{fmt, io};
 
-use reqwest::{Client, StatusCode};
+use reqwest::StatusCode;
 use rocket::http::Status as HttpStatus;
 use rocket::response::{content, Flash, Redirect, Responder};
 use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
 use rocket::Request;
+use time::OffsetDateTime;
 
+use crate::http::{InstanceResponse, RequestContext};
+use crate::models::Ban;
+use crate::web::rate_limit::RetryAfter;
+use crate::web::ErrorNotice;
+
+pub mod backoff;
+pub mod backup;
+pub mod cli;
 pub mod config;
 pub mod db;
 
 pub mod form;
+pub mod http;
+pub mod metrics;
+pub mod misskey;
 pub mod models;
+pub mod policy;
+pub mod report;
+pub mod software;
 pub mod string_ext;
 mod templates;
 pub mod web;
@@ -29,8 +44,34 @@ pub enum FediurlError {
     Url(url::ParseError),
     /// Path is invalid or not found
     InvalidPath,
-    /// An error response from a Mastodon instance
-    ErrorResponse(ErrorResponse),
+    /// The instance rejected the credentials used (401 or 403 response)
+    Unauthorized(ErrorResponse),
+    /// The instance doesn't have the requested resource or endpoint (404 or 410 response)
+    NotFound(ErrorResponse),
+    /// Any other client error response (4xx) from the instance
+    UpstreamClient(ErrorResponse),
+    /// A server error response (5xx) from the instance
+    UpstreamServer(ErrorResponse),
+    /// The user or their instance is banned
+    Banned(Ban),
+    /// The instance with the domain is not permitted by the instance policy
+    InstanceNotPermitted(String),
+    /// Content on the remote domain is blocked by the operator or the user
+    BlockedDomain(String),
+    /// The instance is rate limiting requests, until the time if known
+    RateLimited(Option<OffsetDateTime>),
+    /// The instance didn't respond in time
+    Timeout,
+    /// The instance is considered down, requests won't be made until `retry_at`
+    InstanceUnavailable {
+        domain: String,
+        retry_at: OffsetDateTime,
+    },
+    /// An error from a request to an instance, with the details of the request
+    Request {
+        context: Box<RequestContext>,
+        source: Box<FediurlError>,
+    },
 }
 
 #[derive(Responder)]
@@ -72,12 +113,193 @@ impl fmt::Display for FediurlError {
             FediurlError::InvalidPath => f.write_str("invalid path"),
             FediurlError::Http(err) => err.fmt(f),
             FediurlError::Url(err) => err.fmt(f),
-            FediurlError::ErrorResponse(err) => f.write_str(&err.error_description),
+            FediurlError::Unauthorized(err) => write!(
+                f,
+                "the instance did not accept your credentials, try logging in again: {}",
+                err.error_description
+            ),
+            FediurlError::NotFound(err) => {
+                write!(
+                    f,
+                    "the instance could not find it: {}",
+                    err.error_description
+                )
+            }
+            FediurlError::UpstreamClient(err) => {
+                write!(
+                    f,
+                    "the instance rejected the request: {}",
+                    err.error_description
+                )
+            }
+            FediurlError::UpstreamServer(err) => write!(
+                f,
+                "the instance failed to handle the request: {}",
+                err.error_description
+            ),
+            FediurlError::Banned(ban) => ban.fmt(f),
+            FediurlError::InstanceNotPermitted(domain) => {
+                write!(f, "{} is not permitted to use Fediurl", domain)
+            }
+            FediurlError::BlockedDomain(domain) => write!(f, "content from {} is blocked", domain),
+            FediurlError::RateLimited(Some(reset)) => write!(
+                f,
+                "your instance is rate limiting Fediurl, try again at {:02}:{:02} UTC",
+                reset.hour(),
+                reset.minute()
+            ),
+            FediurlError::Timeout => f.write_str("the instance took too long to respond"),
+            FediurlError::InstanceUnavailable { domain, retry_at } => write!(
+                f,
+                "{} appears to be down, Fediurl will try it again after {:02}:{:02} UTC",
+                domain,
+                retry_at.hour(),
+                retry_at.minute()
+            ),
+            FediurlError::RateLimited(None) => {
+                f.write_str("your instance is rate limiting Fediurl, try again later")
+            }
+            FediurlError::Request { context, source } => write!(f, "{} ({})", source, context),
+        }
+    }
+}
+
+impl std::error::Error for FediurlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FediurlError::Request { source, .. } => Some(source.as_ref()),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for FediurlError {}
+impl FediurlError {
+    /// A short name for the category of the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FediurlError::Database(_) => "database",
+            FediurlError::Http(_) => "http_client",
+            FediurlError::Io(_) => "io",
+            FediurlError::Url(_) => "invalid_url",
+            FediurlError::InvalidPath => "invalid_path",
+            FediurlError::Unauthorized(_) => "unauthorized",
+            FediurlError::NotFound(_) => "not_found",
+            FediurlError::UpstreamClient(_) => "upstream_client_error",
+            FediurlError::UpstreamServer(_) => "upstream_server_error",
+            FediurlError::Banned(_) => "banned",
+            FediurlError::InstanceNotPermitted(_) => "instance_not_permitted",
+            FediurlError::BlockedDomain(_) => "blocked_domain",
+            FediurlError::RateLimited(_) => "rate_limited",
+            FediurlError::Timeout => "timeout",
+            FediurlError::InstanceUnavailable { .. } => "instance_unavailable",
+            FediurlError::Request { source, .. } => source.kind(),
+        }
+    }
+
+    /// The status of the response Fediurl should give for the error
+    pub fn status(&self) -> HttpStatus {
+        match self {
+            FediurlError::Database(sqlx::Error::RowNotFound)
+            | FediurlError::InvalidPath
+            | FediurlError::NotFound(_) => HttpStatus::NotFound,
+            FediurlError::Database(_) | FediurlError::Io(_) => HttpStatus::InternalServerError,
+            FediurlError::Http(err) if err.is_timeout() => HttpStatus::GatewayTimeout,
+            FediurlError::Http(_) => HttpStatus::BadGateway,
+            FediurlError::Url(_) => HttpStatus::BadRequest,
+            FediurlError::Unauthorized(_) => HttpStatus::Unauthorized,
+            FediurlError::UpstreamClient(_) | FediurlError::UpstreamServer(_) => {
+                HttpStatus::BadGateway
+            }
+            FediurlError::Banned(_)
+            | FediurlError::InstanceNotPermitted(_)
+            | FediurlError::BlockedDomain(_) => HttpStatus::Forbidden,
+            FediurlError::RateLimited(_) => HttpStatus::TooManyRequests,
+            FediurlError::Timeout => HttpStatus::GatewayTimeout,
+            FediurlError::InstanceUnavailable { .. } => HttpStatus::ServiceUnavailable,
+            FediurlError::Request { source, .. } => source.status(),
+        }
+    }
+
+    /// The error response from the instance, if the error is one
+    pub fn response(&self) -> Option<&ErrorResponse> {
+        match self {
+            FediurlError::Unauthorized(err)
+            | FediurlError::NotFound(err)
+            | FediurlError::UpstreamClient(err)
+            | FediurlError::UpstreamServer(err) => Some(err),
+            FediurlError::Request { source, .. } => source.response(),
+            _ => None,
+        }
+    }
+
+    /// Attach the details of the request to the instance that failed
+    pub fn with_context(self, context: RequestContext) -> Self {
+        match self {
+            FediurlError::Request { .. } => self,
+            err => FediurlError::Request {
+                context: Box::new(context),
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// The details of the request to the instance that failed, if known
+    pub fn context(&self) -> Option<&RequestContext> {
+        match self {
+            FediurlError::Request { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The error without the details of the request
+    pub fn inner(&self) -> &FediurlError {
+        match self {
+            FediurlError::Request { source, .. } => source,
+            err => err,
+        }
+    }
+
+    /// The error without the details of the request
+    pub fn into_inner(self) -> FediurlError {
+        match self {
+            FediurlError::Request { source, .. } => *source,
+            err => err,
+        }
+    }
+
+    /// Whether the error is a fault in Fediurl, rather than the request or the instance
+    pub fn is_internal(&self) -> bool {
+        self.status() == HttpStatus::InternalServerError
+    }
+
+    /// The error as returned from Fediurl's JSON endpoints
+    pub fn to_error_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            status: self.status().code,
+            error: self.kind().to_string(),
+            error_description: self.inner().to_string(),
+            context: self.context().cloned(),
+        }
+    }
+
+    /// Log the error, reporting it to the error sinks if it's a fault in Fediurl or a failed
+    /// request to an instance
+    pub fn report(&self, location: impl fmt::Display) {
+        let context = self.context();
+        if self.is_internal() {
+            error!("{}: {}", location, self);
+        } else if self.status().class().is_server_error() {
+            warn!("{}: {}", location, self);
+            if context.is_none() {
+                return;
+            }
+        } else {
+            return;
+        }
+
+        report::capture_error(self);
+    }
+}
 
 /// Render a template as HTML
 pub fn html<T: markup::Render + fmt::Display>(template: T) -> content::RawHtml<String> {
@@ -87,13 +309,42 @@ pub fn html<T: markup::Render + fmt::Display>(template: T) -> content::RawHtml<S
 impl<'r> Responder<'r, 'static> for FediurlError {
     fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
         match self {
-            FediurlError::Database(sqlx::Error::RowNotFound) | FediurlError::InvalidPath => {
-                Err(HttpStatus::NotFound)
+            FediurlError::Banned(ban) => web::banned(req, &ban).respond_to(req),
+            FediurlError::RateLimited(reset) => {
+                let retry_after = reset
+                    .map(|reset| reset - OffsetDateTime::now_utc())
+                    .and_then(|wait| wait.try_into().ok());
+                req.local_cache(|| RetryAfter(retry_after));
+                Err(HttpStatus::TooManyRequests)
+            }
+            err @ FediurlError::Request { .. } => {
+                err.report(req.uri());
+                let status = err.status();
+                let context = err.context().cloned();
+                match err.into_inner() {
+                    // Let these render as they would without the context
+                    inner @ (FediurlError::Banned(_) | FediurlError::RateLimited(_)) => {
+                        inner.respond_to(req)
+                    }
+                    inner => {
+                        req.local_cache(|| ErrorNotice {
+                            description: Some(inner.to_string()),
+                            context,
+                        });
+                        Err(status)
+                    }
+                }
             }
-            _ => {
-                error!("{}: {}", req.uri(), self);
-                sentry::capture_error(&self);
-                Err(HttpStatus::InternalServerError)
+            err => {
+                err.report(req.uri());
+                if !err.is_internal() {
+                    // The catcher shows what went wrong with the instance
+                    req.local_cache(|| ErrorNotice {
+                        description: Some(err.to_string()),
+                        context: None,
+                    });
+                }
+                Err(err.status())
             }
         }
     }
@@ -113,6 +364,9 @@ pub struct ErrorResponse {
     pub status: u16,
     pub error: String,
     pub error_description: String,
+    /// The request to the instance that failed, when returned from Fediurl
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub context: Option<RequestContext>,
 }
 
 impl ErrorResponse {
@@ -121,19 +375,22 @@ impl ErrorResponse {
             status: status.as_u16(),
             error: err.error,
             error_description: err.error_description,
+            context: None,
         }
     }
 }
 
 pub(crate) async fn json_or_error<T: DeserializeOwned>(
-    response: reqwest::Response,
+    response: InstanceResponse,
 ) -> Result<T, FediurlError> {
-    if response.status().is_success() {
-        let app = response.json().await?;
-        Ok(app)
+    let (response, context) = response.into_parts();
+    let res = if response.status().is_success() {
+        response.json().await.map_err(FediurlError::from)
+    } else if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let reset = backoff::rate_limit_reset(response.headers());
+        Err(FediurlError::RateLimited(reset))
     } else {
         let status = response.status();
-        // TODO: Distinguish 4xx and 5xx responses
         let err = response
             .json::<MastodonErrorResponse>()
             .await
@@ -142,13 +399,14 @@ pub(crate) async fn json_or_error<T: DeserializeOwned>(
                 status: status.as_u16(),
                 error: "http_client".to_string(),
                 error_description: "Request to instance was unsuccessful.".to_string(),
+                context: None,
             });
-        Err(FediurlError::ErrorResponse(err))
-    }
-}
-
-pub(crate) fn http_client() -> reqwest::Result<Client> {
-    Client::builder()
-        .user_agent(format!("{} {}", NAME, env!("CARGO_PKG_VERSION")))
-        .build()
+        Err(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => FediurlError::Unauthorized(err),
+            StatusCode::NOT_FOUND | StatusCode::GONE => FediurlError::NotFound(err),
+            status if status.is_client_error() => FediurlError::UpstreamClient(err),
+            _ => FediurlError::UpstreamServer(err),
+        })
+    };
+    res.map_err(|err| err.with_context(context))
 }
@@ -1,7 +1,6 @@
-#[macro_use]
-extern crate rocket;
+use std::process::ExitCode;
 
-#[launch]
-async fn rocket() -> _ {
-    fediurl::web::rocket()
+#[rocket::main]
+async fn main() -> ExitCode {
+    fediurl::cli::run(std::env::args().skip(1).collect()).await
 }
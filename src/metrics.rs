@@ -0,0 +1,189 @@
+//! Prometheus metrics, exported at `/metrics` when enabled in the config.
+
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::db::DbPool;
+use crate::software::Software;
+use crate::FediurlError;
+
+/// Buckets for request latencies, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    rewrites: IntCounterVec,
+    software_cache: IntCounterVec,
+    upstream_duration: HistogramVec,
+    logins: IntCounterVec,
+    db_connections: IntGaugeVec,
+}
+
+/// The outcome of a rewrite request
+pub enum RewriteOutcome<'a> {
+    /// The URL was rewritten
+    Hit,
+    /// The instance didn't find anything for the URL
+    NoMatch,
+    Error(&'a FediurlError),
+}
+
+/// The steps of logging in, to see where users drop out
+#[derive(Copy, Clone)]
+pub enum LoginStage {
+    /// The log in form was submitted
+    Started,
+    /// The instance redirected back to Fediurl
+    Authorized,
+    /// The user was logged in
+    Completed,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        // NOTE(unwrap): the names, labels, and buckets of the metrics are known to be valid
+        let registry = Registry::new_custom(Some(crate::NAME.to_lowercase()), None).unwrap();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled, by route"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle requests, by route",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["route"],
+        )
+        .unwrap();
+        let rewrites = IntCounterVec::new(
+            Opts::new("rewrites_total", "Rewrite requests, by outcome"),
+            &["outcome", "error"],
+        )
+        .unwrap();
+        let software_cache = IntCounterVec::new(
+            Opts::new(
+                "software_cache_total",
+                "Lookups of the detected software of instances, by whether it was cached",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time taken by instances to resolve URLs, by instance software",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["software"],
+        )
+        .unwrap();
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Log in attempts reaching each stage"),
+            &["stage"],
+        )
+        .unwrap();
+        let db_connections = IntGaugeVec::new(
+            Opts::new("db_connections", "Database pool connections, by state"),
+            &["state"],
+        )
+        .unwrap();
+
+        let metrics = Metrics {
+            registry,
+            requests,
+            request_duration,
+            rewrites,
+            software_cache,
+            upstream_duration,
+            logins,
+            db_connections,
+        };
+        metrics.register();
+        metrics
+    }
+
+    fn register(&self) {
+        let collectors: [Box<dyn prometheus::core::Collector>; 7] = [
+            Box::new(self.requests.clone()),
+            Box::new(self.request_duration.clone()),
+            Box::new(self.rewrites.clone()),
+            Box::new(self.software_cache.clone()),
+            Box::new(self.upstream_duration.clone()),
+            Box::new(self.logins.clone()),
+            Box::new(self.db_connections.clone()),
+        ];
+        for collector in collectors {
+            // NOTE(unwrap): each metric has a unique name and is only registered once
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        self.requests
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn rewrite(&self, outcome: RewriteOutcome<'_>) {
+        let labels = match outcome {
+            RewriteOutcome::Hit => ["hit", ""],
+            RewriteOutcome::NoMatch => ["no_match", ""],
+            RewriteOutcome::Error(err) => ["error", err.kind()],
+        };
+        self.rewrites.with_label_values(&labels).inc();
+    }
+
+    pub fn software_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.software_cache.with_label_values(&[result]).inc();
+    }
+
+    pub fn observe_upstream(&self, software: Software, elapsed: Duration) {
+        self.upstream_duration
+            .with_label_values(&[software.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn login(&self, stage: LoginStage) {
+        let stage = match stage {
+            LoginStage::Started => "started",
+            LoginStage::Authorized => "authorized",
+            LoginStage::Completed => "completed",
+        };
+        self.logins.with_label_values(&[stage]).inc();
+    }
+
+    /// Render the metrics in the Prometheus text format, `pool` is sampled for its current state
+    pub fn render(&self, pool: &DbPool) -> prometheus::Result<String> {
+        let idle = pool.num_idle() as i64;
+        let size = i64::from(pool.size());
+        self.db_connections.with_label_values(&["idle"]).set(idle);
+        self.db_connections
+            .with_label_values(&["active"])
+            .set(size - idle);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        // NOTE(unwrap): the text encoder only produces UTF-8
+        Ok(String::from_utf8(buf).unwrap())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
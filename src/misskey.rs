@@ -0,0 +1,102 @@
+//! Misskey API client, used for Misskey and its forks (Firefish, Iceshrimp, Sharkey, etc.)
+//!
+//! These servers don't implement the Mastodon app registration and OAuth flow. Users are
+//! authenticated with [MiAuth](https://misskey-hub.net/en/docs/for-developers/api/token/miauth/)
+//! and remote URLs are resolved with the `ap/show` endpoint.
+
+use rocket::serde::json::serde_json::json;
+use rocket::serde::Deserialize;
+use url::Url;
+
+use crate::http::HttpClient;
+use crate::{json_or_error, FediurlError};
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MiAuthCheck {
+    pub ok: bool,
+    pub token: Option<String>,
+    /// The user that authorised the session
+    pub user: Option<User>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", content = "object")]
+pub enum ApObject {
+    User(User),
+    Note(Note),
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    /// Domain of the user, `None` for local users
+    pub host: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Note {
+    pub id: String,
+}
+
+/// Build the URL to send the user to in order to authorise Fediurl
+///
+/// `session` is a unique id for this authorisation request, which is also passed to `check`
+/// once the user returns to `callback`.
+pub fn miauth_url(instance_url: &Url, session: &str, callback: &str) -> Result<Url, FediurlError> {
+    let mut url = instance_url.join("/miauth/")?.join(session)?;
+    url.query_pairs_mut()
+        .append_pair("name", crate::NAME)
+        .append_pair("callback", callback);
+    Ok(url)
+}
+
+/// Exchange a completed MiAuth session for an access token
+pub async fn check(
+    client: &HttpClient,
+    instance_url: &Url,
+    session: &str,
+) -> Result<MiAuthCheck, FediurlError> {
+    let url = instance_url.join(&format!("/api/miauth/{}/check", session))?;
+    let resp = client.send(client.post(url).json(&json!({}))).await?;
+    json_or_error(resp).await
+}
+
+/// Resolve a remote URL to an object on the instance, fetching it if necessary
+pub async fn ap_show(
+    client: &HttpClient,
+    instance_url: &Url,
+    token: &str,
+    uri: &str,
+) -> Result<ApObject, FediurlError> {
+    let url = instance_url.join("/api/ap/show")?;
+    let request = client.post(url).json(&json!({ "i": token, "uri": uri }));
+    let resp = client.send(request).await?;
+    json_or_error(resp).await
+}
+
+impl ApObject {
+    /// The URL of this object on the instance at `instance_url`
+    pub fn url(&self, instance_url: &Url) -> Url {
+        let mut url = instance_url.clone();
+        // NOTE(unwrap): won't panic as instance URL is known to be valid as a base URL
+        match self {
+            ApObject::User(user) => {
+                let acct = match user.host.as_deref() {
+                    Some(host) => format!("@{}@{}", user.username, host),
+                    None => format!("@{}", user.username),
+                };
+                url.path_segments_mut().unwrap().push(&acct);
+            }
+            ApObject::Note(note) => {
+                url.path_segments_mut()
+                    .unwrap()
+                    .extend(&["notes", note.id.as_str()]);
+            }
+        }
+        url
+    }
+}
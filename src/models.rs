@@ -1,5 +1,83 @@
+use std::fmt;
+
+use time::{Duration, OffsetDateTime};
+
+pub mod failure;
+pub mod health;
 pub mod instance;
 pub mod user;
 
 // https://www.sqlite.org/rescode.html#constraint_unique
 pub const SQLITE_CONSTRAINT_UNIQUE: &str = "2067";
+
+/// How often `last_used_at` is updated, so that not every rewrite writes to the database
+pub const LAST_USED_INTERVAL: Duration = Duration::hours(1);
+
+/// An active ban of an instance or user
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub subject: BanSubject,
+    /// When the ban expires, `None` if it is permanent
+    pub until: Option<OffsetDateTime>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BanSubject {
+    /// An instance, with its domain
+    Instance(String),
+    User,
+}
+
+/// Whether `last_used_at` should be updated for a use at `now`
+fn last_used_expired(last_used_at: Option<OffsetDateTime>, now: OffsetDateTime) -> bool {
+    last_used_at.map_or(true, |last_used_at| {
+        now - last_used_at >= LAST_USED_INTERVAL
+    })
+}
+
+impl Ban {
+    /// Returns the ban described by the ban columns of a row if it is currently in effect
+    fn active(
+        subject: BanSubject,
+        banned_at: Option<OffsetDateTime>,
+        banned_until: Option<OffsetDateTime>,
+        reason: Option<&str>,
+    ) -> Option<Ban> {
+        if banned_at.is_none() {
+            return None;
+        }
+        match banned_until {
+            Some(until) if until <= OffsetDateTime::now_utc() => None,
+            until => Some(Ban {
+                subject,
+                until,
+                reason: reason.map(String::from),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.subject {
+            BanSubject::Instance(domain) => {
+                write!(f, "{} has been banned from {}", domain, crate::NAME)?
+            }
+            BanSubject::User => write!(f, "Your account has been banned from {}", crate::NAME)?,
+        }
+        if let Some(until) = self.until {
+            write!(
+                f,
+                " until {} {:02}:{:02} UTC",
+                until.date(),
+                until.hour(),
+                until.minute()
+            )?;
+        }
+        match self.reason.as_deref() {
+            Some(reason) => write!(f, ": {}", reason),
+            None => f.write_str("."),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use time::OffsetDateTime;
+
+use crate::db::DbConnection;
+use crate::models::user::{User, UserId};
+
+/// A failed interaction with an instance
+#[derive(Debug)]
+pub struct Failure {
+    pub id: i64,
+    pub domain: String,
+    pub user_id: Option<UserId>,
+    /// Category of the failure, such as `http_client` or `registration`
+    pub kind: String,
+    pub message: String,
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug)]
+pub struct NewFailure<'a> {
+    pub domain: &'a str,
+    pub user_id: Option<UserId>,
+    pub kind: &'a str,
+    pub message: &'a str,
+}
+
+impl Failure {
+    /// Record a failure
+    pub async fn create(db: &mut DbConnection, failure: NewFailure<'_>) -> Result<(), sqlx::Error> {
+        let NewFailure {
+            domain,
+            user_id,
+            kind,
+            message,
+        } = failure;
+
+        sqlx::query!(
+            "INSERT INTO failures (domain, user_id, kind, message) VALUES ($1, $2, $3, $4)",
+            domain,
+            user_id as _,
+            kind,
+            message
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// The failures recorded for a user, newest first
+    pub async fn for_user(
+        db: &mut DbConnection,
+        user_id: UserId,
+    ) -> Result<Vec<Failure>, sqlx::Error> {
+        sqlx::query_as!(
+            Failure,
+            r#"SELECT
+                id,
+                domain,
+                user_id as "user_id: UserId",
+                kind,
+                message,
+                created_at as "created_at: OffsetDateTime"
+            FROM failures
+            WHERE user_id = $1
+            ORDER BY created_at DESC, id DESC"#,
+            user_id as _
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// The failures recorded for any of the users of `user`'s account, newest first
+    ///
+    /// The users are those returned by `User::same_account`.
+    pub async fn for_account(
+        db: &mut DbConnection,
+        user: &User,
+    ) -> Result<Vec<Failure>, sqlx::Error> {
+        sqlx::query_as!(
+            Failure,
+            r#"SELECT
+                id,
+                domain,
+                user_id as "user_id: UserId",
+                kind,
+                message,
+                created_at as "created_at: OffsetDateTime"
+            FROM failures
+            WHERE user_id IN (
+                SELECT id FROM users WHERE id = $1 OR (instance_id = $2 AND account = $3)
+            )
+            ORDER BY created_at DESC, id DESC"#,
+            user.id as _,
+            user.instance_id as _,
+            user.account
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// The most recent failures, newest first
+    pub async fn recent(db: &mut DbConnection, limit: i64) -> Result<Vec<Failure>, sqlx::Error> {
+        sqlx::query_as!(
+            Failure,
+            r#"SELECT
+                id,
+                domain,
+                user_id as "user_id: UserId",
+                kind,
+                message,
+                created_at as "created_at: OffsetDateTime"
+            FROM failures
+            ORDER BY created_at DESC, id DESC
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(db)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::pool::PoolConnection;
+
+    use super::*;
+    use crate::db::Backend;
+    use crate::models::instance::{Instance, InstanceId, NewInstance};
+    use crate::models::user::NewUser;
+
+    async fn create(db: &mut DbConnection, user_id: Option<UserId>, message: &str) {
+        let failure = NewFailure {
+            domain: "example.social",
+            user_id,
+            kind: "http_client",
+            message,
+        };
+        Failure::create(db, failure).await.unwrap();
+    }
+
+    fn messages(failures: &[Failure]) -> Vec<&str> {
+        failures
+            .iter()
+            .map(|failure| failure.message.as_str())
+            .collect()
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn recent(mut db: PoolConnection<Backend>) {
+        create(&mut *db, None, "first").await;
+        create(&mut *db, Some(UserId::from(1)), "second").await;
+        create(&mut *db, None, "third").await;
+
+        let failures = Failure::recent(&mut *db, 2).await.unwrap();
+        assert_eq!(messages(&failures), ["third", "second"]);
+        let failure = &failures[1];
+        assert_eq!(failure.domain, "example.social");
+        assert_eq!(failure.user_id, Some(UserId::from(1)));
+        assert_eq!(failure.kind, "http_client");
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn for_user(mut db: PoolConnection<Backend>) {
+        let user_id = UserId::from(1);
+        create(&mut *db, Some(user_id), "first").await;
+        create(&mut *db, Some(UserId::from(2)), "other").await;
+        create(&mut *db, None, "none").await;
+        create(&mut *db, Some(user_id), "second").await;
+
+        let failures = Failure::for_user(&mut *db, user_id).await.unwrap();
+        assert_eq!(messages(&failures), ["second", "first"]);
+    }
+
+    async fn create_user(
+        db: &mut DbConnection,
+        instance_id: InstanceId,
+        account: Option<&str>,
+    ) -> UserId {
+        let new_user = NewUser {
+            instance_id,
+            access_token: String::from("token"),
+            account: account.map(String::from),
+            scopes: None,
+        };
+        User::create(db, new_user).await.unwrap()
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn for_account(mut db: PoolConnection<Backend>) {
+        let new_instance = NewInstance {
+            domain: String::from("example.social"),
+            client_id: String::new(),
+            client_secret: String::new(),
+            software: None,
+            scopes: String::new(),
+        };
+        let instance_id = Instance::create(&mut *db, new_instance).await.unwrap();
+        let first = create_user(&mut *db, instance_id, Some("alice@example.social")).await;
+        let other = create_user(&mut *db, instance_id, Some("bob@example.social")).await;
+        let anonymous = create_user(&mut *db, instance_id, None).await;
+        let second = create_user(&mut *db, instance_id, Some("alice@example.social")).await;
+        create(&mut *db, Some(first), "first").await;
+        create(&mut *db, Some(other), "other").await;
+        create(&mut *db, Some(anonymous), "anonymous").await;
+        create(&mut *db, None, "none").await;
+        create(&mut *db, Some(second), "second").await;
+
+        let user = User::from_id(&mut *db, first).await.unwrap();
+        let failures = Failure::for_account(&mut *db, &user).await.unwrap();
+        assert_eq!(messages(&failures), ["second", "first"]);
+
+        // Users without an account only have their own failures
+        let user = User::from_id(&mut *db, anonymous).await.unwrap();
+        let failures = Failure::for_account(&mut *db, &user).await.unwrap();
+        assert_eq!(messages(&failures), ["anonymous"]);
+    }
+}
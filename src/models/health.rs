@@ -0,0 +1,87 @@
+use time::OffsetDateTime;
+
+use crate::db::DbConnection;
+
+/// A change in the availability of an instance
+#[derive(Debug)]
+pub struct HealthEvent {
+    pub id: i64,
+    pub domain: String,
+    /// Whether the instance came back up, or went down
+    pub up: bool,
+    /// The failure that caused the instance to be considered down
+    pub message: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+impl HealthEvent {
+    /// Record a change in the availability of an instance
+    pub async fn create(
+        db: &mut DbConnection,
+        domain: &str,
+        up: bool,
+        message: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO instance_health (domain, up, message) VALUES ($1, $2, $3)",
+            domain,
+            up,
+            message
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// The most recent changes in availability, newest first
+    pub async fn recent(
+        db: &mut DbConnection,
+        limit: i64,
+    ) -> Result<Vec<HealthEvent>, sqlx::Error> {
+        sqlx::query_as!(
+            HealthEvent,
+            r#"SELECT
+                id,
+                domain,
+                up as "up: bool",
+                message,
+                created_at as "created_at: OffsetDateTime"
+            FROM instance_health
+            ORDER BY created_at DESC, id DESC
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(db)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::pool::PoolConnection;
+
+    use super::*;
+    use crate::db::Backend;
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn recent(mut db: PoolConnection<Backend>) {
+        HealthEvent::create(&mut *db, "a.example", false, Some("timed out"))
+            .await
+            .unwrap();
+        HealthEvent::create(&mut *db, "b.example", false, Some("connection refused"))
+            .await
+            .unwrap();
+        HealthEvent::create(&mut *db, "a.example", true, None)
+            .await
+            .unwrap();
+
+        let events = HealthEvent::recent(&mut *db, 2).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].domain, "a.example");
+        assert!(events[0].up);
+        assert_eq!(events[0].message, None);
+        assert_eq!(events[1].domain, "b.example");
+        assert!(!events[1].up);
+        assert_eq!(events[1].message.as_deref(), Some("connection refused"));
+    }
+}
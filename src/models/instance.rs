@@ -1,20 +1,74 @@
-use sqlx::SqliteConnection;
-use time::OffsetDateTime;
+use std::fmt;
+
+use sqlx::Connection;
+use time::{Duration, OffsetDateTime};
 use url::Url;
 
+use crate::db::{self, DbConnection};
+use crate::models::{self, Ban, BanSubject};
+use crate::software::Software;
+
+/// How long to wait before detecting the software of an instance again after detection failed
+pub const SOFTWARE_RETRY_INTERVAL: Duration = Duration::days(1);
+
 #[derive(sqlx::Type, Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[sqlx(transparent)]
 pub struct InstanceId(i64);
 
+impl InstanceId {
+    pub fn value(&self) -> i64 {
+        self.0
+    }
+}
+
+impl From<i64> for InstanceId {
+    fn from(id: i64) -> Self {
+        InstanceId(id)
+    }
+}
+
+impl fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Debug)]
 pub struct Instance {
     pub id: InstanceId,
     pub domain: String,
+    /// OAuth client id, empty for instances that authenticate with MiAuth
     pub client_id: String,
+    /// OAuth client secret, empty for instances that authenticate with MiAuth
     pub client_secret: String,
+    /// Name of the software the instance runs, as reported by NodeInfo
+    pub software: Option<String>,
+    /// When the software was last detected, whether or not that succeeded
+    pub software_detected_at: Option<OffsetDateTime>,
+    /// The search API that is known to work on the instance
+    pub search_api: Option<String>,
+    /// OAuth scopes the application was registered with
+    pub scopes: String,
     pub banned_until: Option<OffsetDateTime>,
+    pub banned_at: Option<OffsetDateTime>,
+    pub ban_reason: Option<String>,
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
+    /// When a user of the instance last rewrote a URL, updated at most every `LAST_USED_INTERVAL`
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// The API used to search for remote URLs on an instance
+///
+/// Searching is the only way to resolve a remote URL on Mastodon compatible servers. Endpoints
+/// such as `/api/v1/statuses/:id` take the instance's own id for a status, which is only known
+/// once a search has resolved it, so they can't be used as a further fallback.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SearchApi {
+    /// `/api/v2/search`, supported by Mastodon 2.4.1 and later and most compatible servers
+    V2,
+    /// `/api/v1/search`, supported by older Mastodon and some Pleroma versions
+    V1,
 }
 
 // TODO: These probably don't need to be owned strings
@@ -23,10 +77,28 @@ pub struct NewInstance {
     pub domain: String,
     pub client_id: String,
     pub client_secret: String,
+    pub software: Option<String>,
+    pub scopes: String,
+}
+
+/// An instance with statistics about its use, for the admin interface
+#[derive(Debug)]
+pub struct InstanceSummary {
+    pub id: InstanceId,
+    pub domain: String,
+    pub software: Option<String>,
+    pub search_api: Option<String>,
+    pub banned_until: Option<OffsetDateTime>,
+    pub banned_at: Option<OffsetDateTime>,
+    pub ban_reason: Option<String>,
+    pub user_count: i64,
+    /// Time of the most recent log in from a user of this instance
+    pub last_login: Option<OffsetDateTime>,
+    pub failure_count: i64,
 }
 
 macro_rules! instance_query {
-    ($field:literal, $arg:tt) => {
+    ($field:literal, $($arg:tt)+) => {
         sqlx::query_as!(
             Instance,
             r#"SELECT
@@ -34,14 +106,21 @@ macro_rules! instance_query {
                 domain,
                 client_id,
                 client_secret,
+                software,
+                software_detected_at as "software_detected_at: OffsetDateTime",
+                search_api,
+                scopes,
                 banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
                 created_at as "created_at: OffsetDateTime",
-                updated_at as "updated_at: OffsetDateTime"
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
             FROM instances
             WHERE "#
                 + $field
-                + " = ?",
-            $arg,
+                + " = $1",
+            $($arg)+,
         )
     };
 }
@@ -49,49 +128,619 @@ macro_rules! instance_query {
 impl Instance {
     /// Inserts a new user into the database and returns its id
     pub async fn create(
-        db: &mut SqliteConnection,
+        db: &mut DbConnection,
         instance: NewInstance,
     ) -> Result<InstanceId, sqlx::Error> {
         let NewInstance {
             domain,
             client_id,
             client_secret,
+            software,
+            scopes,
         } = instance;
 
-        let res = sqlx::query!(
-            "INSERT INTO instances (domain, client_id, client_secret) VALUES (?, ?, ?)",
+        sqlx::query_scalar!(
+            r#"INSERT INTO instances (domain, client_id, client_secret, software, scopes)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id as "id!: InstanceId""#,
             domain,
             client_id,
-            client_secret
+            client_secret,
+            software,
+            scopes
         )
-        .execute(db)
-        .await?;
-
-        Ok(InstanceId(res.last_insert_rowid()))
+        .fetch_one(db)
+        .await
     }
 
-    pub async fn from_id(
-        db: &mut SqliteConnection,
-        id: InstanceId,
-    ) -> Result<Instance, sqlx::Error> {
-        instance_query!("id", id).fetch_one(db).await
+    pub async fn from_id(db: &mut DbConnection, id: InstanceId) -> Result<Instance, sqlx::Error> {
+        instance_query!("id", id as _).fetch_one(db).await
     }
 
-    pub async fn from_domain(
-        db: &mut SqliteConnection,
-        domain: &str,
-    ) -> Result<Instance, sqlx::Error> {
+    pub async fn from_domain(db: &mut DbConnection, domain: &str) -> Result<Instance, sqlx::Error> {
         instance_query!("domain", domain).fetch_one(db).await
     }
 
+    /// All instances, ordered by domain
+    pub async fn all(db: &mut DbConnection) -> Result<Vec<Instance>, sqlx::Error> {
+        sqlx::query_as!(
+            Instance,
+            r#"SELECT
+                id as "id: InstanceId",
+                domain,
+                client_id,
+                client_secret,
+                software,
+                software_detected_at as "software_detected_at: OffsetDateTime",
+                search_api,
+                scopes,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                created_at as "created_at: OffsetDateTime",
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
+            FROM instances
+            ORDER BY domain"#
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Insert a copy of `instance`, such as one from an export, and return its new id
+    ///
+    /// All fields are copied except for the id.
+    pub async fn restore(
+        db: &mut DbConnection,
+        instance: &Instance,
+    ) -> Result<InstanceId, sqlx::Error> {
+        let banned_until = instance.banned_until.map(db::timestamp);
+        let banned_at = instance.banned_at.map(db::timestamp);
+        let created_at = db::timestamp(instance.created_at);
+        let updated_at = db::timestamp(instance.updated_at);
+        let software_detected_at = instance.software_detected_at.map(db::timestamp);
+        let last_used_at = instance.last_used_at.map(db::timestamp);
+        sqlx::query_scalar!(
+            r#"INSERT INTO instances (
+                domain,
+                client_id,
+                client_secret,
+                software,
+                software_detected_at,
+                search_api,
+                scopes,
+                banned_until,
+                banned_at,
+                ban_reason,
+                created_at,
+                updated_at,
+                last_used_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            RETURNING id as "id!: InstanceId""#,
+            instance.domain,
+            instance.client_id,
+            instance.client_secret,
+            instance.software,
+            software_detected_at,
+            instance.search_api,
+            instance.scopes,
+            banned_until,
+            banned_at,
+            instance.ban_reason,
+            created_at,
+            updated_at,
+            last_used_at
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /// All instances along with statistics about their use, ordered by domain
+    pub async fn summaries(db: &mut DbConnection) -> Result<Vec<InstanceSummary>, sqlx::Error> {
+        sqlx::query_as!(
+            InstanceSummary,
+            r#"SELECT
+                id as "id: InstanceId",
+                domain,
+                software,
+                search_api,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                (SELECT COUNT(*) FROM users WHERE users.instance_id = instances.id) as "user_count!: i64",
+                (SELECT MAX(created_at) FROM users WHERE users.instance_id = instances.id) as "last_login: OffsetDateTime",
+                (SELECT COUNT(*) FROM failures WHERE failures.domain = instances.domain) as "failure_count!: i64"
+            FROM instances
+            ORDER BY domain"#
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Delete the instance and its users
+    pub async fn delete(db: &mut DbConnection, id: InstanceId) -> Result<(), sqlx::Error> {
+        let mut tx = db.begin().await?;
+        sqlx::query!("DELETE FROM users WHERE instance_id = $1", id as _)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("DELETE FROM instances WHERE id = $1", id as _)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await
+    }
+
     pub async fn from_domain_optional(
-        db: &mut SqliteConnection,
+        db: &mut DbConnection,
         domain: &str,
     ) -> Result<Option<Instance>, sqlx::Error> {
         instance_query!("domain", domain).fetch_optional(db).await
     }
 
+    /// Replace the OAuth client credentials of the instance and the scopes they were issued for
+    pub async fn update_credentials(
+        db: &mut DbConnection,
+        id: InstanceId,
+        client_id: &str,
+        client_secret: &str,
+        scopes: &str,
+    ) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE instances
+            SET client_id = $1, client_secret = $2, scopes = $3, updated_at = $4
+            WHERE id = $5",
+            client_id,
+            client_secret,
+            scopes,
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Record the software the instance is running
+    ///
+    /// `"unknown"` records that detection failed, it is tried again after
+    /// `SOFTWARE_RETRY_INTERVAL`.
+    pub async fn update_software(
+        db: &mut DbConnection,
+        id: InstanceId,
+        software: &str,
+    ) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE instances
+            SET software = $1, software_detected_at = $2, updated_at = $2
+            WHERE id = $3",
+            software,
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Record the search API that works on the instance
+    pub async fn update_search_api(
+        db: &mut DbConnection,
+        id: InstanceId,
+        search_api: SearchApi,
+    ) -> Result<(), sqlx::Error> {
+        let search_api = search_api.as_str();
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE instances SET search_api = $1, updated_at = $2 WHERE id = $3",
+            search_api,
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Record that the instance was used at `now`, unless that was recorded recently
+    pub async fn record_use(
+        &self,
+        db: &mut DbConnection,
+        now: OffsetDateTime,
+    ) -> Result<(), sqlx::Error> {
+        if !models::last_used_expired(self.last_used_at, now) {
+            return Ok(());
+        }
+        let now = db::timestamp(now);
+        sqlx::query!(
+            "UPDATE instances SET last_used_at = $1 WHERE id = $2",
+            now,
+            self.id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget the detected software and search API of all instances so they are detected again
+    pub async fn clear_detected(db: &mut DbConnection) -> Result<u64, sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        let res = sqlx::query!(
+            "UPDATE instances
+            SET software = NULL, software_detected_at = NULL, search_api = NULL, updated_at = $1",
+            now
+        )
+        .execute(db)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// The search API that is known to work on the instance, if any
+    pub fn search_api(&self) -> Option<SearchApi> {
+        self.search_api.as_deref().and_then(SearchApi::from_name)
+    }
+
+    /// The software the instance is running, if known
+    pub fn software(&self) -> Option<Software> {
+        self.software.as_deref().map(Software::from_name)
+    }
+
+    /// Whether the software should be detected at `now`
+    ///
+    /// That is when it hasn't been detected yet, or when detection failed at least
+    /// `SOFTWARE_RETRY_INTERVAL` ago.
+    pub fn detection_due(&self, now: OffsetDateTime) -> bool {
+        match self.software.as_deref() {
+            None => true,
+            Some(software) if software == Software::Unknown.as_str() => {
+                self.software_detected_at.map_or(true, |detected_at| {
+                    now - detected_at >= SOFTWARE_RETRY_INTERVAL
+                })
+            }
+            Some(_) => false,
+        }
+    }
+
     pub(crate) fn url(&self) -> Url {
         format!("https://{}", self.domain).parse().unwrap()
     }
+
+    /// Ban the instance, optionally until a particular time
+    pub async fn ban(
+        db: &mut DbConnection,
+        id: InstanceId,
+        until: Option<OffsetDateTime>,
+        reason: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        let until = until.map(db::timestamp);
+        sqlx::query!(
+            "UPDATE instances
+            SET banned_at = $1, banned_until = $2, ban_reason = $3, updated_at = $1
+            WHERE id = $4",
+            now,
+            until,
+            reason,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Lift any ban on the instance
+    pub async fn unban(db: &mut DbConnection, id: InstanceId) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE instances
+            SET banned_at = NULL, banned_until = NULL, ban_reason = NULL, updated_at = $1
+            WHERE id = $2",
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// The ban on the instance, if one is currently in effect
+    pub fn active_ban(&self) -> Option<Ban> {
+        Ban::active(
+            BanSubject::Instance(self.domain.clone()),
+            self.banned_at,
+            self.banned_until,
+            self.ban_reason.as_deref(),
+        )
+    }
+}
+
+impl SearchApi {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchApi::V2 => "v2",
+            SearchApi::V1 => "v1",
+        }
+    }
+
+    fn from_name(s: &str) -> Option<SearchApi> {
+        match s {
+            "v2" => Some(SearchApi::V2),
+            "v1" => Some(SearchApi::V1),
+            _ => None,
+        }
+    }
+
+    /// Path of the search endpoint
+    pub fn path(&self) -> &'static str {
+        match self {
+            SearchApi::V2 => "/api/v2/search",
+            SearchApi::V1 => "/api/v1/search",
+        }
+    }
+
+    /// The API to fall back to if this one isn't supported
+    pub fn fallback(&self) -> SearchApi {
+        match self {
+            SearchApi::V2 => SearchApi::V1,
+            SearchApi::V1 => SearchApi::V2,
+        }
+    }
+}
+
+impl InstanceSummary {
+    /// Whether the software of the instance was successfully detected
+    pub fn software_detected(&self) -> bool {
+        self.software
+            .as_deref()
+            .map_or(false, |software| software != Software::Unknown.as_str())
+    }
+
+    /// The ban on the instance, if one is currently in effect
+    pub fn active_ban(&self) -> Option<Ban> {
+        Ban::active(
+            BanSubject::Instance(self.domain.clone()),
+            self.banned_at,
+            self.banned_until,
+            self.ban_reason.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::pool::PoolConnection;
+    use time::Duration;
+
+    use super::*;
+    use crate::db::Backend;
+    use crate::models::user::{NewUser, User};
+
+    async fn create(db: &mut DbConnection, domain: &str) -> InstanceId {
+        let new_instance = NewInstance {
+            domain: domain.to_string(),
+            client_id: String::from("client id"),
+            client_secret: String::from("client secret"),
+            software: Some(String::from("mastodon")),
+            scopes: String::from("read:search"),
+        };
+        Instance::create(db, new_instance).await.unwrap()
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn create_and_find(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.id, id);
+        assert_eq!(instance.domain, "example.social");
+        assert_eq!(instance.client_id, "client id");
+        assert_eq!(instance.client_secret, "client secret");
+        assert_eq!(instance.software(), Some(Software::Mastodon));
+        assert_eq!(instance.search_api(), None);
+        assert_eq!(instance.scopes, "read:search");
+        assert!(instance.active_ban().is_none());
+        assert_eq!(instance.last_used_at, None);
+
+        let instance = Instance::from_domain(&mut *db, "example.social")
+            .await
+            .unwrap();
+        assert_eq!(instance.id, id);
+        let instance = Instance::from_domain_optional(&mut *db, "example.social")
+            .await
+            .unwrap();
+        assert_eq!(instance.map(|instance| instance.id), Some(id));
+        let instance = Instance::from_domain_optional(&mut *db, "other.social")
+            .await
+            .unwrap();
+        assert!(instance.is_none());
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn domains_are_unique(mut db: PoolConnection<Backend>) {
+        create(&mut *db, "example.social").await;
+        let new_instance = NewInstance {
+            domain: String::from("example.social"),
+            client_id: String::new(),
+            client_secret: String::new(),
+            software: None,
+            scopes: String::new(),
+        };
+        assert!(Instance::create(&mut *db, new_instance).await.is_err());
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn all_ordered_by_domain(mut db: PoolConnection<Backend>) {
+        create(&mut *db, "b.example").await;
+        create(&mut *db, "a.example").await;
+
+        let domains = Instance::all(&mut *db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|instance| instance.domain)
+            .collect::<Vec<_>>();
+        assert_eq!(domains, ["a.example", "b.example"]);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn updates(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+
+        Instance::update_credentials(
+            &mut *db,
+            id,
+            "new id",
+            "new secret",
+            "read:search read:blocks",
+        )
+        .await
+        .unwrap();
+        Instance::update_search_api(&mut *db, id, SearchApi::V1)
+            .await
+            .unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.client_id, "new id");
+        assert_eq!(instance.client_secret, "new secret");
+        assert_eq!(instance.scopes, "read:search read:blocks");
+        assert_eq!(instance.search_api(), Some(SearchApi::V1));
+
+        assert_eq!(Instance::clear_detected(&mut *db).await.unwrap(), 1);
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.software, None);
+        assert_eq!(instance.software_detected_at, None);
+        assert_eq!(instance.search_api, None);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn failed_detection_is_retried_later(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        let now = OffsetDateTime::now_utc();
+        assert!(!instance.detection_due(now));
+
+        Instance::clear_detected(&mut *db).await.unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert!(instance.detection_due(now));
+
+        Instance::update_software(&mut *db, id, "unknown")
+            .await
+            .unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.software(), Some(Software::Unknown));
+        assert!(!instance.detection_due(now));
+        assert!(instance.detection_due(now + SOFTWARE_RETRY_INTERVAL));
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn ban_and_unban(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+
+        let until = OffsetDateTime::now_utc() + Duration::days(1);
+        Instance::ban(&mut *db, id, Some(until), Some("spam"))
+            .await
+            .unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        let ban = instance.active_ban().unwrap();
+        assert_eq!(ban.reason.as_deref(), Some("spam"));
+        assert_eq!(
+            ban.until.map(OffsetDateTime::unix_timestamp),
+            Some(until.unix_timestamp())
+        );
+
+        // Expired bans are not in effect
+        let until = OffsetDateTime::now_utc() - Duration::days(1);
+        Instance::ban(&mut *db, id, Some(until), None)
+            .await
+            .unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert!(instance.banned_at.is_some());
+        assert!(instance.active_ban().is_none());
+
+        Instance::ban(&mut *db, id, None, None).await.unwrap();
+        Instance::unban(&mut *db, id).await.unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert!(instance.banned_at.is_none());
+        assert!(instance.active_ban().is_none());
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn record_use(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        instance.record_use(&mut *db, now).await.unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.last_used_at, Some(now));
+
+        // Uses within the interval aren't recorded
+        let later = now + Duration::minutes(1);
+        instance.record_use(&mut *db, later).await.unwrap();
+        let instance = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(instance.last_used_at, Some(now));
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn restore(mut db: PoolConnection<Backend>) {
+        let time = |timestamp| OffsetDateTime::from_unix_timestamp(timestamp).unwrap();
+        let instance = Instance {
+            id: InstanceId::from(0),
+            domain: String::from("example.social"),
+            client_id: String::from("client id"),
+            client_secret: String::from("client secret"),
+            software: Some(String::from("akkoma")),
+            software_detected_at: Some(time(1_700_000_100)),
+            search_api: Some(String::from("v1")),
+            scopes: String::from("read:search"),
+            banned_until: Some(time(1_800_000_000)),
+            banned_at: Some(time(1_700_000_300)),
+            ban_reason: Some(String::from("spam")),
+            created_at: time(1_700_000_000),
+            updated_at: time(1_700_000_300),
+            last_used_at: Some(time(1_700_000_200)),
+        };
+        let id = Instance::restore(&mut *db, &instance).await.unwrap();
+
+        let restored = Instance::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(restored.domain, instance.domain);
+        assert_eq!(restored.client_id, instance.client_id);
+        assert_eq!(restored.client_secret, instance.client_secret);
+        assert_eq!(restored.software, instance.software);
+        assert_eq!(restored.software_detected_at, instance.software_detected_at);
+        assert_eq!(restored.search_api, instance.search_api);
+        assert_eq!(restored.scopes, instance.scopes);
+        assert_eq!(restored.banned_until, instance.banned_until);
+        assert_eq!(restored.banned_at, instance.banned_at);
+        assert_eq!(restored.ban_reason, instance.ban_reason);
+        assert_eq!(restored.created_at, instance.created_at);
+        assert_eq!(restored.updated_at, instance.updated_at);
+        assert_eq!(restored.last_used_at, instance.last_used_at);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn delete_removes_users(mut db: PoolConnection<Backend>) {
+        let id = create(&mut *db, "example.social").await;
+        let other_id = create(&mut *db, "other.social").await;
+        for instance_id in [id, other_id] {
+            let new_user = NewUser {
+                instance_id,
+                access_token: String::from("token"),
+                account: None,
+                scopes: None,
+            };
+            User::create(&mut *db, new_user).await.unwrap();
+        }
+
+        Instance::delete(&mut *db, id).await.unwrap();
+        assert!(matches!(
+            Instance::from_id(&mut *db, id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(User::for_instance(&mut *db, id).await.unwrap().is_empty());
+        assert_eq!(
+            User::for_instance(&mut *db, other_id).await.unwrap().len(),
+            1
+        );
+    }
 }
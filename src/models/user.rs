@@ -1,10 +1,12 @@
 use std::fmt;
 use std::str::FromStr;
 
-use sqlx::SqliteConnection;
+use sqlx::Connection;
 use time::OffsetDateTime;
 
+use crate::db::{self, DbConnection};
 use crate::models::instance::{Instance, InstanceId};
+use crate::models::{self, Ban, BanSubject};
 
 #[derive(sqlx::Type, Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[sqlx(transparent)]
@@ -15,9 +17,17 @@ pub struct User {
     pub id: UserId,
     pub instance_id: InstanceId,
     pub access_token: String,
+    /// The user's account in `user@domain` form, if known
+    pub account: Option<String>,
+    /// Space separated scopes granted to the access token, if known
+    pub scopes: Option<String>,
     pub banned_until: Option<OffsetDateTime>,
+    pub banned_at: Option<OffsetDateTime>,
+    pub ban_reason: Option<String>,
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
+    /// When the user last rewrote a URL, updated at most every `LAST_USED_INTERVAL`
+    pub last_used_at: Option<OffsetDateTime>,
 }
 
 // TODO: These probably don't need to be owned strings
@@ -25,6 +35,21 @@ pub struct User {
 pub struct NewUser {
     pub instance_id: InstanceId,
     pub access_token: String,
+    pub account: Option<String>,
+    pub scopes: Option<String>,
+}
+
+/// A user along with the domain of their instance, for the admin interface
+#[derive(Debug)]
+pub struct UserSummary {
+    pub id: UserId,
+    pub account: Option<String>,
+    pub domain: String,
+    pub banned_until: Option<OffsetDateTime>,
+    pub banned_at: Option<OffsetDateTime>,
+    pub ban_reason: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub last_used_at: Option<OffsetDateTime>,
 }
 
 impl UserId {
@@ -54,49 +79,651 @@ impl fmt::Display for UserId {
 }
 
 macro_rules! user_query {
-    ($field:literal, $arg:tt) => {
+    ($field:literal, $($arg:tt)+) => {
         sqlx::query_as!(
             User,
             r#"SELECT
                 id as "id: UserId",
                 instance_id as "instance_id: InstanceId",
                 access_token,
+                account,
+                scopes,
                 banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
                 created_at as "created_at: OffsetDateTime",
-                updated_at as "updated_at: OffsetDateTime"
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
             FROM users
             WHERE "#
                 + $field
-                + " = ?",
-            $arg,
+                + " = $1",
+            $($arg)+,
         )
     };
 }
 
 impl User {
     /// Inserts a new user into the database and returns its id
-    pub async fn create(db: &mut SqliteConnection, user: NewUser) -> Result<UserId, sqlx::Error> {
+    pub async fn create(db: &mut DbConnection, user: NewUser) -> Result<UserId, sqlx::Error> {
         let NewUser {
             instance_id,
             access_token,
+            account,
+            scopes,
         } = user;
 
-        let res = sqlx::query!(
-            "INSERT INTO users (instance_id, access_token) VALUES (?, ?)",
-            instance_id,
-            access_token
+        sqlx::query_scalar!(
+            r#"INSERT INTO users (instance_id, access_token, account, scopes)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id as "id!: UserId""#,
+            instance_id as _,
+            access_token,
+            account,
+            scopes
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    pub async fn from_id(db: &mut DbConnection, user_id: UserId) -> Result<User, sqlx::Error> {
+        user_query!("id", user_id as _).fetch_one(db).await
+    }
+
+    /// The user for `account` on an instance, the most recent if there are several
+    pub async fn from_account(
+        db: &mut DbConnection,
+        instance_id: InstanceId,
+        account: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT
+                id as "id: UserId",
+                instance_id as "instance_id: InstanceId",
+                access_token,
+                account,
+                scopes,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                created_at as "created_at: OffsetDateTime",
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            WHERE instance_id = $1 AND account = $2
+            ORDER BY id DESC
+            LIMIT 1"#,
+            instance_id as _,
+            account
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// The users for the same account on the same instance as `user`, including `user`, oldest
+    /// first
+    ///
+    /// Logging in again reuses the user for the account, but there may be several users for it
+    /// from before that was the case. A user whose account isn't known only matches itself.
+    pub async fn same_account(
+        db: &mut DbConnection,
+        user: &User,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT
+                id as "id: UserId",
+                instance_id as "instance_id: InstanceId",
+                access_token,
+                account,
+                scopes,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                created_at as "created_at: OffsetDateTime",
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            WHERE id = $1 OR (instance_id = $2 AND account = $3)
+            ORDER BY id"#,
+            user.id as _,
+            user.instance_id as _,
+            user.account
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// The users of an instance, oldest first
+    pub async fn for_instance(
+        db: &mut DbConnection,
+        instance_id: InstanceId,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT
+                id as "id: UserId",
+                instance_id as "instance_id: InstanceId",
+                access_token,
+                account,
+                scopes,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                created_at as "created_at: OffsetDateTime",
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            WHERE instance_id = $1
+            ORDER BY id"#,
+            instance_id as _
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Insert a copy of `user`, such as one from an export, and return its new id
+    ///
+    /// All fields are copied except for the id.
+    pub async fn restore(db: &mut DbConnection, user: &User) -> Result<UserId, sqlx::Error> {
+        let banned_until = user.banned_until.map(db::timestamp);
+        let banned_at = user.banned_at.map(db::timestamp);
+        let created_at = db::timestamp(user.created_at);
+        let updated_at = db::timestamp(user.updated_at);
+        let last_used_at = user.last_used_at.map(db::timestamp);
+        sqlx::query_scalar!(
+            r#"INSERT INTO users (
+                instance_id,
+                access_token,
+                account,
+                scopes,
+                banned_until,
+                banned_at,
+                ban_reason,
+                created_at,
+                updated_at,
+                last_used_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING id as "id!: UserId""#,
+            user.instance_id as _,
+            user.access_token,
+            user.account,
+            user.scopes,
+            banned_until,
+            banned_at,
+            user.ban_reason,
+            created_at,
+            updated_at,
+            last_used_at
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    /// The most recently created users along with their instance domain
+    pub async fn recent_summaries(
+        db: &mut DbConnection,
+        limit: i64,
+    ) -> Result<Vec<UserSummary>, sqlx::Error> {
+        sqlx::query_as!(
+            UserSummary,
+            r#"SELECT
+                users.id as "id: UserId",
+                users.account,
+                instances.domain,
+                users.banned_until as "banned_until: OffsetDateTime",
+                users.banned_at as "banned_at: OffsetDateTime",
+                users.ban_reason,
+                users.created_at as "created_at: OffsetDateTime",
+                users.last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            JOIN instances ON instances.id = users.instance_id
+            ORDER BY users.created_at DESC
+            LIMIT $1"#,
+            limit
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Users that have been banned, including those whose ban has expired
+    pub async fn banned_summaries(db: &mut DbConnection) -> Result<Vec<UserSummary>, sqlx::Error> {
+        sqlx::query_as!(
+            UserSummary,
+            r#"SELECT
+                users.id as "id: UserId",
+                users.account,
+                instances.domain,
+                users.banned_until as "banned_until: OffsetDateTime",
+                users.banned_at as "banned_at: OffsetDateTime",
+                users.ban_reason,
+                users.created_at as "created_at: OffsetDateTime",
+                users.last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            JOIN instances ON instances.id = users.instance_id
+            WHERE users.banned_at IS NOT NULL
+            ORDER BY users.banned_at DESC"#
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Delete the user and the failures recorded for them
+    pub async fn delete(db: &mut DbConnection, id: UserId) -> Result<(), sqlx::Error> {
+        User::delete_all(db, &[id]).await
+    }
+
+    /// Delete the users and the failures recorded for them, either all of them are deleted or
+    /// none are
+    pub async fn delete_all(db: &mut DbConnection, ids: &[UserId]) -> Result<(), sqlx::Error> {
+        let mut tx = db.begin().await?;
+        for &id in ids {
+            sqlx::query!("DELETE FROM failures WHERE user_id = $1", id as _)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query!("DELETE FROM users WHERE id = $1", id as _)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Users that haven't been used since `cutoff`, or logged in since then if they never were
+    pub async fn inactive(
+        db: &mut DbConnection,
+        cutoff: OffsetDateTime,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        let cutoff = db::timestamp(cutoff);
+        sqlx::query_as!(
+            User,
+            r#"SELECT
+                id as "id: UserId",
+                instance_id as "instance_id: InstanceId",
+                access_token,
+                account,
+                scopes,
+                banned_until as "banned_until: OffsetDateTime",
+                banned_at as "banned_at: OffsetDateTime",
+                ban_reason,
+                created_at as "created_at: OffsetDateTime",
+                updated_at as "updated_at: OffsetDateTime",
+                last_used_at as "last_used_at: OffsetDateTime"
+            FROM users
+            WHERE COALESCE(last_used_at, created_at) < $1
+            ORDER BY instance_id, id"#,
+            cutoff
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Record that the user rewrote a URL at `now`, unless that was recorded recently
+    pub async fn record_use(
+        &self,
+        db: &mut DbConnection,
+        now: OffsetDateTime,
+    ) -> Result<(), sqlx::Error> {
+        if !models::last_used_expired(self.last_used_at, now) {
+            return Ok(());
+        }
+        let now = db::timestamp(now);
+        sqlx::query!(
+            "UPDATE users SET last_used_at = $1 WHERE id = $2",
+            now,
+            self.id as _
         )
         .execute(db)
         .await?;
-
-        Ok(UserId(res.last_insert_rowid()))
+        Ok(())
     }
 
-    pub async fn from_id(db: &mut SqliteConnection, user_id: UserId) -> Result<User, sqlx::Error> {
-        user_query!("id", user_id).fetch_one(db).await
+    /// Replace the access token and scopes of a user that has logged in again
+    pub async fn update_token(
+        db: &mut DbConnection,
+        id: UserId,
+        access_token: &str,
+        scopes: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE users SET access_token = $1, scopes = $2, updated_at = $3 WHERE id = $4",
+            access_token,
+            scopes,
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
     }
 
-    pub async fn instance(&self, db: &mut SqliteConnection) -> Result<Instance, sqlx::Error> {
+    pub async fn instance(&self, db: &mut DbConnection) -> Result<Instance, sqlx::Error> {
         Instance::from_id(&mut *db, self.instance_id).await
     }
+
+    /// Ban the user, optionally until a particular time
+    pub async fn ban(
+        db: &mut DbConnection,
+        id: UserId,
+        until: Option<OffsetDateTime>,
+        reason: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        let until = until.map(db::timestamp);
+        sqlx::query!(
+            "UPDATE users
+            SET banned_at = $1, banned_until = $2, ban_reason = $3, updated_at = $1
+            WHERE id = $4",
+            now,
+            until,
+            reason,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Lift any ban on the user
+    pub async fn unban(db: &mut DbConnection, id: UserId) -> Result<(), sqlx::Error> {
+        let now = db::timestamp(OffsetDateTime::now_utc());
+        sqlx::query!(
+            "UPDATE users
+            SET banned_at = NULL, banned_until = NULL, ban_reason = NULL, updated_at = $1
+            WHERE id = $2",
+            now,
+            id as _
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Whether the user's access token was granted `scope`
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.as_deref().map_or(false, |scopes| {
+            scopes.split_whitespace().any(|s| s == scope)
+        })
+    }
+
+    /// The ban on the user, if one is currently in effect
+    pub fn active_ban(&self) -> Option<Ban> {
+        Ban::active(
+            BanSubject::User,
+            self.banned_at,
+            self.banned_until,
+            self.ban_reason.as_deref(),
+        )
+    }
+
+    /// The ban on the user, or on another user for the same account, if one is currently in
+    /// effect
+    ///
+    /// Bans are recorded against a single user, so this is what should be checked to stop a
+    /// banned user from getting around it by logging in again.
+    pub async fn account_ban(&self, db: &mut DbConnection) -> Result<Option<Ban>, sqlx::Error> {
+        let users = User::same_account(db, self).await?;
+        Ok(users.iter().find_map(User::active_ban))
+    }
+}
+
+impl UserSummary {
+    /// The ban on the user, if one is currently in effect
+    pub fn active_ban(&self) -> Option<Ban> {
+        Ban::active(
+            BanSubject::User,
+            self.banned_at,
+            self.banned_until,
+            self.ban_reason.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::pool::PoolConnection;
+    use time::Duration;
+
+    use super::*;
+    use crate::db::Backend;
+    use crate::models::failure::{Failure, NewFailure};
+    use crate::models::instance::NewInstance;
+
+    async fn create_instance(db: &mut DbConnection) -> InstanceId {
+        let new_instance = NewInstance {
+            domain: String::from("example.social"),
+            client_id: String::new(),
+            client_secret: String::new(),
+            software: None,
+            scopes: String::new(),
+        };
+        Instance::create(db, new_instance).await.unwrap()
+    }
+
+    async fn create(
+        db: &mut DbConnection,
+        instance_id: InstanceId,
+        access_token: &str,
+        account: Option<&str>,
+    ) -> UserId {
+        let new_user = NewUser {
+            instance_id,
+            access_token: access_token.to_string(),
+            account: account.map(String::from),
+            scopes: Some(String::from("read:search read:blocks")),
+        };
+        User::create(db, new_user).await.unwrap()
+    }
+
+    fn ids(users: &[User]) -> Vec<UserId> {
+        users.iter().map(|user| user.id).collect()
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn create_and_find(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let id = create(&mut *db, instance_id, "token", Some("alice@example.social")).await;
+
+        let user = User::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(user.instance_id, instance_id);
+        assert_eq!(user.access_token, "token");
+        assert_eq!(user.account.as_deref(), Some("alice@example.social"));
+        assert!(user.has_scope("read:blocks"));
+        assert!(!user.has_scope("read"));
+        assert!(user.active_ban().is_none());
+        assert_eq!(user.instance(&mut *db).await.unwrap().id, instance_id);
+
+        let user = User::from_account(&mut *db, instance_id, "alice@example.social")
+            .await
+            .unwrap();
+        assert_eq!(user.map(|user| user.id), Some(id));
+        let user = User::from_account(&mut *db, instance_id, "bob@example.social")
+            .await
+            .unwrap();
+        assert!(user.is_none());
+
+        let users = User::for_instance(&mut *db, instance_id).await.unwrap();
+        assert_eq!(ids(&users), [id]);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn same_account(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let first = create(&mut *db, instance_id, "first", Some("alice@example.social")).await;
+        let second = create(
+            &mut *db,
+            instance_id,
+            "second",
+            Some("alice@example.social"),
+        )
+        .await;
+        let unknown = create(&mut *db, instance_id, "unknown", None).await;
+        let other = create(&mut *db, instance_id, "other", Some("bob@example.social")).await;
+
+        // The most recent user for the account is found
+        let user = User::from_account(&mut *db, instance_id, "alice@example.social")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, second);
+
+        let users = User::same_account(&mut *db, &user).await.unwrap();
+        assert_eq!(ids(&users), [first, second]);
+
+        let user = User::from_id(&mut *db, unknown).await.unwrap();
+        let users = User::same_account(&mut *db, &user).await.unwrap();
+        assert_eq!(ids(&users), [unknown]);
+
+        let user = User::from_id(&mut *db, other).await.unwrap();
+        let users = User::same_account(&mut *db, &user).await.unwrap();
+        assert_eq!(ids(&users), [other]);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn ban_and_unban(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let first = create(&mut *db, instance_id, "first", Some("alice@example.social")).await;
+        let second = create(
+            &mut *db,
+            instance_id,
+            "second",
+            Some("alice@example.social"),
+        )
+        .await;
+
+        let until = OffsetDateTime::now_utc() + Duration::days(1);
+        User::ban(&mut *db, first, Some(until), Some("spam"))
+            .await
+            .unwrap();
+        let user = User::from_id(&mut *db, first).await.unwrap();
+        let ban = user.active_ban().unwrap();
+        assert_eq!(ban.reason.as_deref(), Some("spam"));
+
+        // The ban applies to the other user for the account too
+        let user = User::from_id(&mut *db, second).await.unwrap();
+        assert!(user.active_ban().is_none());
+        assert!(user.account_ban(&mut *db).await.unwrap().is_some());
+
+        let summaries = User::banned_summaries(&mut *db).await.unwrap();
+        assert_eq!(
+            summaries.iter().map(|user| user.id).collect::<Vec<_>>(),
+            [first]
+        );
+
+        User::unban(&mut *db, first).await.unwrap();
+        let user = User::from_id(&mut *db, first).await.unwrap();
+        assert!(user.banned_at.is_none());
+        assert!(user.account_ban(&mut *db).await.unwrap().is_none());
+        assert!(User::banned_summaries(&mut *db).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn update_token(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let id = create(&mut *db, instance_id, "old", Some("alice@example.social")).await;
+
+        User::update_token(&mut *db, id, "new", Some("read:search"))
+            .await
+            .unwrap();
+        let user = User::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(user.access_token, "new");
+        assert_eq!(user.scopes.as_deref(), Some("read:search"));
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn record_use_and_inactive(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let used = create(&mut *db, instance_id, "used", None).await;
+        let unused = create(&mut *db, instance_id, "unused", None).await;
+
+        let now = OffsetDateTime::now_utc();
+        let user = User::from_id(&mut *db, used).await.unwrap();
+        user.record_use(&mut *db, now + Duration::days(30))
+            .await
+            .unwrap();
+        let user = User::from_id(&mut *db, used).await.unwrap();
+        assert_eq!(
+            user.last_used_at.map(OffsetDateTime::unix_timestamp),
+            Some((now + Duration::days(30)).unix_timestamp())
+        );
+
+        let inactive = User::inactive(&mut *db, now + Duration::days(1))
+            .await
+            .unwrap();
+        assert_eq!(ids(&inactive), [unused]);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn restore(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let time = |timestamp| OffsetDateTime::from_unix_timestamp(timestamp).unwrap();
+        let user = User {
+            id: UserId::from(0),
+            instance_id,
+            access_token: String::from("token"),
+            account: Some(String::from("alice@example.social")),
+            scopes: Some(String::from("read:search")),
+            banned_until: None,
+            banned_at: Some(time(1_700_000_300)),
+            ban_reason: Some(String::from("spam")),
+            created_at: time(1_700_000_000),
+            updated_at: time(1_700_000_300),
+            last_used_at: Some(time(1_700_000_200)),
+        };
+        let id = User::restore(&mut *db, &user).await.unwrap();
+
+        let restored = User::from_id(&mut *db, id).await.unwrap();
+        assert_eq!(restored.instance_id, user.instance_id);
+        assert_eq!(restored.access_token, user.access_token);
+        assert_eq!(restored.account, user.account);
+        assert_eq!(restored.scopes, user.scopes);
+        assert_eq!(restored.banned_until, user.banned_until);
+        assert_eq!(restored.banned_at, user.banned_at);
+        assert_eq!(restored.ban_reason, user.ban_reason);
+        assert_eq!(restored.created_at, user.created_at);
+        assert_eq!(restored.updated_at, user.updated_at);
+        assert_eq!(restored.last_used_at, user.last_used_at);
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn delete_removes_failures(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let id = create(&mut *db, instance_id, "token", None).await;
+        let other_id = create(&mut *db, instance_id, "other", None).await;
+        for user_id in [id, other_id] {
+            let failure = NewFailure {
+                domain: "example.social",
+                user_id: Some(user_id),
+                kind: "http_client",
+                message: "timed out",
+            };
+            Failure::create(&mut *db, failure).await.unwrap();
+        }
+
+        User::delete(&mut *db, id).await.unwrap();
+        assert!(matches!(
+            User::from_id(&mut *db, id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(Failure::for_user(&mut *db, id).await.unwrap().is_empty());
+        assert_eq!(
+            Failure::for_user(&mut *db, other_id).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[sqlx::test(migrator = "crate::db::MIGRATOR")]
+    async fn delete_all(mut db: PoolConnection<Backend>) {
+        let instance_id = create_instance(&mut *db).await;
+        let first = create(&mut *db, instance_id, "first", None).await;
+        let second = create(&mut *db, instance_id, "second", None).await;
+        let other = create(&mut *db, instance_id, "other", None).await;
+
+        User::delete_all(&mut *db, &[first, second]).await.unwrap();
+        let users = User::for_instance(&mut *db, instance_id).await.unwrap();
+        assert_eq!(ids(&users), [other]);
+    }
 }
@@ -0,0 +1,199 @@
+//! Operator policy on which instances may use Fediurl.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use crate::config::{AppConfig, InstancePolicyConfig};
+use crate::models::user::UserId;
+
+/// How long the domains a user has blocked are cached for
+const USER_BLOCKS_TTL: Duration = Duration::from_secs(5 * 60);
+/// Number of cached users above which expired entries are discarded
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Restrictions on the instances that are permitted to log in and rewrite URLs
+#[derive(Debug, Default)]
+pub struct InstancePolicy {
+    /// When present only matching instances are permitted
+    allow: Option<DomainList>,
+    deny: DomainList,
+}
+
+/// Remote domains that the operator doesn't want content resolved from
+#[derive(Debug, Default)]
+pub struct BlockedDomains(pub DomainList);
+
+/// The domains users have blocked on their instance, cached briefly so that they aren't fetched
+/// for every rewrite
+#[derive(Default)]
+pub struct UserBlocks {
+    entries: Mutex<HashMap<UserId, CachedBlocks>>,
+}
+
+struct CachedBlocks {
+    blocks: Arc<DomainList>,
+    fetched: Instant,
+}
+
+/// A list of domains, where entries of the form `*.example.com` match `example.com` and all of
+/// its subdomains
+#[derive(Debug, Default)]
+pub struct DomainList {
+    entries: Vec<DomainPattern>,
+}
+
+#[derive(Debug)]
+struct DomainPattern {
+    domain: String,
+    subdomains: bool,
+}
+
+impl InstancePolicy {
+    /// Build the policy from config, reading any referenced files
+    pub fn load(config: &InstancePolicyConfig) -> io::Result<InstancePolicy> {
+        let allow = match (&config.allow, &config.allow_file) {
+            (None, None) => None,
+            (allow, file) => {
+                let mut list = DomainList::new(allow.iter().flatten());
+                if let Some(path) = file {
+                    list.extend(DomainList::from_file(path)?);
+                }
+                Some(list)
+            }
+        };
+        let mut deny = DomainList::new(&config.deny);
+        if let Some(path) = &config.deny_file {
+            deny.extend(DomainList::from_file(path)?);
+        }
+
+        Ok(InstancePolicy { allow, deny })
+    }
+
+    /// Whether users of the instance at `domain` may use Fediurl
+    pub fn permits(&self, domain: &str) -> bool {
+        let allowed = self
+            .allow
+            .as_ref()
+            .map_or(true, |allow| allow.matches(domain));
+        allowed && !self.deny.matches(domain)
+    }
+}
+
+impl BlockedDomains {
+    /// Build the list of blocked domains from config, reading the file if set
+    pub fn load(config: &AppConfig) -> io::Result<BlockedDomains> {
+        let mut blocked = DomainList::new(&config.blocked_domains);
+        if let Some(path) = &config.blocked_domains_file {
+            blocked.extend(DomainList::from_file(path)?);
+        }
+        Ok(BlockedDomains(blocked))
+    }
+}
+
+impl UserBlocks {
+    /// The domains `user` has blocked, if they were fetched recently
+    pub fn get(&self, user: UserId) -> Option<Arc<DomainList>> {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&user)
+            .filter(|cached| cached.fetched.elapsed() < USER_BLOCKS_TTL)
+            .map(|cached| Arc::clone(&cached.blocks))
+    }
+
+    /// Cache the domains `user` has blocked, returning them for use
+    pub fn insert(&self, user: UserId, blocks: DomainList) -> Arc<DomainList> {
+        let blocks = Arc::new(blocks);
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > PRUNE_THRESHOLD {
+            entries.retain(|_, cached| cached.fetched.elapsed() < USER_BLOCKS_TTL);
+        }
+        entries.insert(
+            user,
+            CachedBlocks {
+                blocks: Arc::clone(&blocks),
+                fetched: Instant::now(),
+            },
+        );
+        blocks
+    }
+}
+
+impl DomainList {
+    pub fn new<I, S>(domains: I) -> DomainList
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let entries = domains
+            .into_iter()
+            .filter_map(|domain| DomainPattern::parse(domain.as_ref()))
+            .collect();
+        DomainList { entries }
+    }
+
+    /// Read a list of domains from a file
+    ///
+    /// The file may contain one domain per line, or be a domain block list in the CSV format
+    /// exported by Mastodon. In the latter case only suspended domains are included, obfuscated
+    /// domains (those containing `*`) are skipped. Blank lines and lines starting with `#` are
+    /// ignored.
+    pub fn from_file(path: &Path) -> io::Result<DomainList> {
+        let contents = fs::read_to_string(path)?;
+        let domains = contents.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+
+            let mut fields = line.split(',');
+            let domain = fields.next()?.trim();
+            match fields.next().map(str::trim) {
+                None | Some("suspend") => Some(domain),
+                Some(_) => None,
+            }
+        });
+        Ok(DomainList::new(domains))
+    }
+
+    pub fn extend(&mut self, other: DomainList) {
+        self.entries.extend(other.entries)
+    }
+
+    /// Whether `domain` matches any entry in the list
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.entries.iter().any(|pattern| pattern.matches(&domain))
+    }
+}
+
+impl DomainPattern {
+    fn parse(entry: &str) -> Option<DomainPattern> {
+        let entry = entry.trim().trim_end_matches('.').to_ascii_lowercase();
+        let (domain, subdomains) = match entry.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (entry.as_str(), false),
+        };
+
+        if domain.is_empty() || domain.contains('*') {
+            warn!("ignoring invalid domain pattern '{}'", entry);
+            return None;
+        }
+        Some(DomainPattern {
+            domain: domain.to_string(),
+            subdomains,
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        domain == self.domain
+            || (self.subdomains
+                && domain
+                    .strip_suffix(&self.domain)
+                    .map_or(false, |prefix| prefix.ends_with('.')))
+    }
+}
@@ -0,0 +1,275 @@
+//! Reporting of errors and traces to the sinks enabled at build time.
+//!
+//! With the `sentry` feature errors are sent to Sentry when `sentry_dsn` is configured. With the
+//! `otel` feature requests to Fediurl and to instances are exported as spans to the OTLP endpoint
+//! in `otel.endpoint`, failed upstream requests and server errors are marked as errors on their
+//! spans. Without either feature errors are only logged.
+
+use rocket::fairing::AdHoc;
+
+use crate::http::RequestContext;
+use crate::FediurlError;
+
+/// Attach the fairings that initialise the configured sinks
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Error reporting", |rocket| async {
+        let rocket = rocket.attach(AdHoc::try_on_ignite("Sentry", sentry_sink::init));
+        otel_sink::attach(rocket)
+    })
+}
+
+/// Report an error that is a fault in Fediurl or a failed request to an instance
+pub fn capture_error(err: &FediurlError) {
+    sentry_sink::capture_error(err);
+}
+
+/// A span covering a request to an instance
+pub struct UpstreamSpan(otel_sink::Span);
+
+impl UpstreamSpan {
+    pub fn start(context: &RequestContext) -> UpstreamSpan {
+        UpstreamSpan(otel_sink::Span::upstream(context))
+    }
+
+    /// End the span, `context` has the status and time taken filled in
+    pub fn end(self, context: &RequestContext, err: Option<&FediurlError>) {
+        self.0.end(context, err)
+    }
+}
+
+#[cfg(feature = "sentry")]
+mod sentry_sink {
+    use rocket::fairing;
+    use rocket::{Build, Rocket};
+
+    use crate::config::AppConfig;
+    use crate::FediurlError;
+
+    pub async fn init(mut rocket: Rocket<Build>) -> fairing::Result {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = rocket.state::<AppConfig>().unwrap();
+
+        if let Some(dsn) = config.sentry_dsn.as_deref() {
+            let guard = sentry::init((
+                dsn,
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    attach_stacktrace: true,
+                    ..Default::default()
+                },
+            ));
+            info!("Sentry initialised");
+            rocket = rocket.manage(guard)
+        } else {
+            info!("No Sentry DSN")
+        }
+
+        Ok(rocket)
+    }
+
+    pub fn capture_error(err: &FediurlError) {
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("error.kind", err.kind());
+                if let Some(context) = err.context() {
+                    scope.set_tag("instance.domain", &context.domain);
+                    scope.set_tag("instance.endpoint", &context.endpoint);
+                    scope.set_tag("instance.method", &context.method);
+                    if let Some(status) = context.status {
+                        scope.set_tag("instance.status", status);
+                    }
+                    scope.set_extra("instance.elapsed_ms", context.elapsed_ms.into());
+                }
+            },
+            || sentry::capture_error(err),
+        );
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+mod sentry_sink {
+    use rocket::fairing;
+    use rocket::{Build, Rocket};
+
+    use crate::config::AppConfig;
+    use crate::FediurlError;
+
+    pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = rocket.state::<AppConfig>().unwrap();
+        if config.sentry_dsn.is_some() {
+            warn!("sentry_dsn is set but Fediurl was built without the sentry feature");
+        }
+        Ok(rocket)
+    }
+
+    pub fn capture_error(_err: &FediurlError) {}
+}
+
+#[cfg(feature = "otel")]
+mod otel_sink {
+    use std::sync::Mutex;
+
+    use opentelemetry::sdk::trace as sdktrace;
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::trace::{Span as _, SpanKind, Status, Tracer};
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+    use rocket::{Build, Data, Orbit, Request, Response, Rocket};
+
+    use crate::config::AppConfig;
+    use crate::http::RequestContext;
+    use crate::FediurlError;
+
+    const TRACER_NAME: &str = "fediurl";
+
+    pub fn attach(rocket: Rocket<Build>) -> Rocket<Build> {
+        rocket.attach(AdHoc::try_on_ignite("OpenTelemetry", |rocket| async {
+            // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+            let config = rocket.state::<AppConfig>().unwrap();
+            let Some(endpoint) = config.otel.endpoint.as_deref() else {
+                info!("No OpenTelemetry endpoint");
+                return Ok(rocket);
+            };
+
+            let exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint);
+            let resource = Resource::new([KeyValue::new(
+                "service.name",
+                config.otel.service_name.clone(),
+            )]);
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(sdktrace::config().with_resource(resource))
+                .install_batch(opentelemetry::runtime::Tokio);
+            match tracer {
+                Ok(_) => {
+                    info!("OpenTelemetry initialised");
+                    Ok(rocket.attach(RequestSpans))
+                }
+                Err(err) => {
+                    error!("Failed to initialise OpenTelemetry: {}", err);
+                    Err(rocket)
+                }
+            }
+        }))
+    }
+
+    /// Exports a span for each request handled
+    struct RequestSpans;
+
+    /// Request local state holding the span of the request until the response is sent
+    struct RequestSpan(Mutex<Option<global::BoxedSpan>>);
+
+    #[rocket::async_trait]
+    impl Fairing for RequestSpans {
+        fn info(&self) -> Info {
+            Info {
+                name: "Request spans",
+                kind: Kind::Request | Kind::Response | Kind::Shutdown,
+            }
+        }
+
+        async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+            let tracer = global::tracer(TRACER_NAME);
+            let span = tracer
+                .span_builder(request.method().as_str())
+                .with_kind(SpanKind::Server)
+                .with_attributes(vec![KeyValue::new(
+                    "http.method",
+                    request.method().as_str(),
+                )])
+                .start(&tracer);
+            request.local_cache(|| RequestSpan(Mutex::new(Some(span))));
+        }
+
+        async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+            let RequestSpan(span) = request.local_cache(|| RequestSpan(Mutex::new(None)));
+            // NOTE(unwrap): the lock is not held across anything that can panic
+            let Some(mut span) = span.lock().unwrap().take() else {
+                return;
+            };
+
+            // The path isn't recorded as rewrite requests include the URL being visited
+            if let Some(name) = request.route().and_then(|route| route.name.as_deref()) {
+                span.update_name(format!("{} {}", request.method(), name));
+                span.set_attribute(KeyValue::new("http.route", name.to_string()));
+            }
+            let status = response.status();
+            span.set_attribute(KeyValue::new("http.status_code", i64::from(status.code)));
+            if status.class().is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+            span.end();
+        }
+
+        async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+            global::shutdown_tracer_provider();
+        }
+    }
+
+    pub struct Span(global::BoxedSpan);
+
+    impl Span {
+        pub fn upstream(context: &RequestContext) -> Span {
+            let tracer = global::tracer(TRACER_NAME);
+            let span = tracer
+                .span_builder(format!("{} {}", context.method, context.domain))
+                .with_kind(SpanKind::Client)
+                .with_attributes(vec![
+                    KeyValue::new("http.method", context.method.clone()),
+                    KeyValue::new("net.peer.name", context.domain.clone()),
+                    KeyValue::new("http.target", context.endpoint.clone()),
+                ])
+                .start(&tracer);
+            Span(span)
+        }
+
+        pub fn end(mut self, context: &RequestContext, err: Option<&FediurlError>) {
+            if let Some(status) = context.status {
+                self.0
+                    .set_attribute(KeyValue::new("http.status_code", i64::from(status)));
+            }
+            if let Some(err) = err {
+                self.0
+                    .set_attribute(KeyValue::new("error.kind", err.kind()));
+                self.0.set_status(Status::error(err.to_string()));
+            }
+            self.0.end();
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod otel_sink {
+    use rocket::fairing::AdHoc;
+    use rocket::{Build, Rocket};
+
+    use crate::config::AppConfig;
+    use crate::http::RequestContext;
+    use crate::FediurlError;
+
+    pub fn attach(rocket: Rocket<Build>) -> Rocket<Build> {
+        rocket.attach(AdHoc::on_ignite("OpenTelemetry", |rocket| async {
+            // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+            let config = rocket.state::<AppConfig>().unwrap();
+            if config.otel.endpoint.is_some() {
+                warn!("otel.endpoint is set but Fediurl was built without the otel feature");
+            }
+            rocket
+        }))
+    }
+
+    pub struct Span;
+
+    impl Span {
+        pub fn upstream(_context: &RequestContext) -> Span {
+            Span
+        }
+
+        pub fn end(self, _context: &RequestContext, _err: Option<&FediurlError>) {}
+    }
+}
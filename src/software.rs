@@ -0,0 +1,290 @@
+//! Detection of the software an instance is running and construction of URLs to content on it.
+
+use rocket::serde::Deserialize;
+use url::Url;
+
+use crate::http::HttpClient;
+use crate::{json_or_error, FediurlError};
+
+const NODEINFO_SCHEMA_PREFIX: &str = "http://nodeinfo.diaspora.software/ns/schema/2.";
+
+/// Fediverse server software that Fediurl knows how to build URLs for
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Software {
+    Mastodon,
+    Pleroma,
+    Akkoma,
+    GoToSocial,
+    Misskey,
+    Firefish,
+    Iceshrimp,
+    Sharkey,
+    /// Software we don't have specific support for, URLs are built as for Mastodon
+    Unknown,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct WellKnownNodeInfo {
+    links: Vec<NodeInfoLink>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NodeInfoLink {
+    rel: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NodeInfo {
+    software: NodeInfoSoftware,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NodeInfoSoftware {
+    name: String,
+}
+
+/// Determine the name of the software running on an instance via NodeInfo
+///
+/// The returned name is lowercase, as reported by the instance. It can be turned into a
+/// `Software` with `Software::from_name`.
+pub async fn detect(client: &HttpClient, instance_url: &Url) -> Result<String, FediurlError> {
+    let url = instance_url.join("/.well-known/nodeinfo")?;
+    let resp = client.send(client.get(url)).await?;
+    let well_known = json_or_error::<WellKnownNodeInfo>(resp).await?;
+
+    // Use the newest 2.x schema on offer
+    let Some(link) = well_known
+        .links
+        .iter()
+        .filter(|link| link.rel.starts_with(NODEINFO_SCHEMA_PREFIX))
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+    else {
+        return Err(FediurlError::InvalidPath);
+    };
+
+    let url = Url::parse(&link.href)?;
+    let resp = client.send(client.get(url)).await?;
+    let nodeinfo = json_or_error::<NodeInfo>(resp).await?;
+
+    Ok(nodeinfo.software.name.to_lowercase())
+}
+
+impl Software {
+    /// Map a NodeInfo software name to a `Software` value
+    pub fn from_name(name: &str) -> Software {
+        match name.to_lowercase().as_str() {
+            "mastodon" | "hometown" | "glitchsoc" | "glitch-soc" => Software::Mastodon,
+            "pleroma" => Software::Pleroma,
+            "akkoma" => Software::Akkoma,
+            "gotosocial" => Software::GoToSocial,
+            "misskey" | "calckey" | "foundkey" | "meisskey" | "cherrypick" => Software::Misskey,
+            "firefish" => Software::Firefish,
+            "iceshrimp" => Software::Iceshrimp,
+            "sharkey" => Software::Sharkey,
+            _ => Software::Unknown,
+        }
+    }
+
+    /// A name for the software, suitable as a metric label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Software::Mastodon => "mastodon",
+            Software::Pleroma => "pleroma",
+            Software::Akkoma => "akkoma",
+            Software::GoToSocial => "gotosocial",
+            Software::Misskey => "misskey",
+            Software::Firefish => "firefish",
+            Software::Iceshrimp => "iceshrimp",
+            Software::Sharkey => "sharkey",
+            Software::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the software is Misskey or one of its forks
+    ///
+    /// These don't support Mastodon's OAuth flow and are instead authenticated with MiAuth.
+    pub fn is_misskey(&self) -> bool {
+        matches!(
+            self,
+            Software::Misskey | Software::Firefish | Software::Iceshrimp | Software::Sharkey
+        )
+    }
+
+    /// Build the URL of a status on the instance at `instance_url`
+    ///
+    /// `acct` and `id` are the account and status id as returned by the instance's search API.
+    /// Returns `None` if the software has no web page for the status.
+    pub fn status_url(&self, instance_url: &Url, acct: &str, id: &str) -> Option<Url> {
+        let mut url = instance_url.clone();
+        match self {
+            Software::Mastodon | Software::Unknown => {
+                let acct = format!("@{}", acct);
+                // NOTE(unwrap): won't panic as instance URL is known to be valid as a base URL
+                url.path_segments_mut()
+                    .unwrap()
+                    .extend(&[acct.as_str(), id]);
+            }
+            Software::Pleroma | Software::Akkoma => {
+                url.path_segments_mut().unwrap().extend(&["notice", id]);
+            }
+            Software::Misskey | Software::Firefish | Software::Iceshrimp | Software::Sharkey => {
+                url.path_segments_mut().unwrap().extend(&["notes", id]);
+            }
+            // GoToSocial only has web pages for local statuses, which are handled by the
+            // caller via the status URL.
+            Software::GoToSocial => return None,
+        }
+        Some(url)
+    }
+
+    /// Build the URL of an account on the instance at `instance_url`
+    ///
+    /// `acct` and `id` are the account name and id as returned by the instance's search API.
+    /// Returns `None` if the software has no web page for the account.
+    pub fn account_url(&self, instance_url: &Url, acct: &str, id: &str) -> Option<Url> {
+        let mut url = instance_url.clone();
+        match self {
+            Software::Mastodon
+            | Software::Unknown
+            | Software::Misskey
+            | Software::Firefish
+            | Software::Iceshrimp
+            | Software::Sharkey => {
+                let acct = format!("@{}", acct);
+                url.path_segments_mut().unwrap().push(&acct);
+            }
+            Software::Pleroma | Software::Akkoma => {
+                url.path_segments_mut().unwrap().extend(&["users", id]);
+            }
+            Software::GoToSocial => return None,
+        }
+        Some(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Software; 9] = [
+        Software::Mastodon,
+        Software::Pleroma,
+        Software::Akkoma,
+        Software::GoToSocial,
+        Software::Misskey,
+        Software::Firefish,
+        Software::Iceshrimp,
+        Software::Sharkey,
+        Software::Unknown,
+    ];
+
+    fn instance_url() -> Url {
+        Url::parse("https://example.social").unwrap()
+    }
+
+    fn status_url(software: Software) -> Option<String> {
+        software
+            .status_url(&instance_url(), "alice@remote.example", "1234")
+            .map(String::from)
+    }
+
+    fn account_url(software: Software) -> Option<String> {
+        software
+            .account_url(&instance_url(), "alice@remote.example", "abcd")
+            .map(String::from)
+    }
+
+    #[test]
+    fn from_name() {
+        for software in ALL {
+            assert_eq!(Software::from_name(software.as_str()), software);
+        }
+    }
+
+    #[test]
+    fn from_name_aliases() {
+        let aliases = [
+            ("hometown", Software::Mastodon),
+            ("glitchsoc", Software::Mastodon),
+            ("glitch-soc", Software::Mastodon),
+            ("calckey", Software::Misskey),
+            ("foundkey", Software::Misskey),
+            ("meisskey", Software::Misskey),
+            ("cherrypick", Software::Misskey),
+        ];
+        for (name, software) in aliases {
+            assert_eq!(Software::from_name(name), software, "{}", name);
+        }
+    }
+
+    #[test]
+    fn from_name_is_case_insensitive() {
+        assert_eq!(Software::from_name("Mastodon"), Software::Mastodon);
+        assert_eq!(Software::from_name("GoToSocial"), Software::GoToSocial);
+    }
+
+    #[test]
+    fn from_name_unknown() {
+        assert_eq!(Software::from_name("friendica"), Software::Unknown);
+        assert_eq!(Software::from_name(""), Software::Unknown);
+    }
+
+    #[test]
+    fn mastodon_urls() {
+        for software in [Software::Mastodon, Software::Unknown] {
+            assert_eq!(
+                status_url(software).as_deref(),
+                Some("https://example.social/@alice@remote.example/1234")
+            );
+            assert_eq!(
+                account_url(software).as_deref(),
+                Some("https://example.social/@alice@remote.example")
+            );
+        }
+    }
+
+    #[test]
+    fn pleroma_urls() {
+        for software in [Software::Pleroma, Software::Akkoma] {
+            assert_eq!(
+                status_url(software).as_deref(),
+                Some("https://example.social/notice/1234")
+            );
+            assert_eq!(
+                account_url(software).as_deref(),
+                Some("https://example.social/users/abcd")
+            );
+        }
+    }
+
+    #[test]
+    fn misskey_urls() {
+        for software in [
+            Software::Misskey,
+            Software::Firefish,
+            Software::Iceshrimp,
+            Software::Sharkey,
+        ] {
+            assert!(software.is_misskey());
+            assert_eq!(
+                status_url(software).as_deref(),
+                Some("https://example.social/notes/1234")
+            );
+            assert_eq!(
+                account_url(software).as_deref(),
+                Some("https://example.social/@alice@remote.example")
+            );
+        }
+    }
+
+    #[test]
+    fn gotosocial_has_no_remote_pages() {
+        assert_eq!(status_url(Software::GoToSocial), None);
+        assert_eq!(account_url(Software::GoToSocial), None);
+    }
+}
@@ -1,10 +1,15 @@
+pub mod account;
+pub mod admin;
+mod errors;
 pub mod form;
 mod home;
 mod layout;
 pub mod session;
 
 use rocket::request::FlashMessage;
+use time::OffsetDateTime;
 
+pub use errors::{Banned, ErrorPage, TooManyRequests};
 pub use home::{Home, Privacy};
 pub use layout::{Layout, Nil, Title};
 
@@ -26,3 +31,16 @@ markup::define! {
         }
     }
 }
+
+fn format_time(time: OffsetDateTime) -> String {
+    format!(
+        "{} {:02}:{:02} UTC",
+        time.date(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+fn format_optional_time(time: Option<OffsetDateTime>) -> String {
+    time.map(format_time).unwrap_or_default()
+}
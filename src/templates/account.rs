@@ -0,0 +1,71 @@
+use super::{format_optional_time, format_time};
+use crate::models::failure::Failure;
+use crate::models::instance::Instance;
+use crate::models::user::User;
+
+markup::define! {
+    Account<'a>(user: &'a User, instance: &'a Instance, failures: &'a [Failure]) {
+        section {
+            h3 { "Your Data" }
+            dl {
+                dt { "Account" }
+                dd { @user.account.as_deref().unwrap_or("Unknown") }
+                dt { "Instance" }
+                dd { @instance.domain }
+                dt { "Logged in" }
+                dd { @format_time(user.created_at) }
+                dt { "Last used" }
+                dd { @format_optional_time(user.last_used_at) }
+                dt { "Permissions" }
+                dd { @user.scopes.as_deref().unwrap_or("Unknown") }
+            }
+            p {
+                "Fediurl stores the access token your instance issued when you logged in, and uses "
+                "it to look up links on your behalf. The token is not shown or included in the download."
+            }
+            @if !failures.is_empty() {
+                h4 { "Recorded Errors" }
+                table {
+                    thead {
+                        tr {
+                            th { "Time" }
+                            th { "Kind" }
+                            th { "Message" }
+                        }
+                    }
+                    tbody {
+                        @for failure in failures.iter() {
+                            tr {
+                                td { @format_time(failure.created_at) }
+                                td { @failure.kind }
+                                td { @failure.message }
+                            }
+                        }
+                    }
+                }
+            }
+            p {
+                a[href = uri!(crate::web::account::export).to_string()] { "Download my data" }
+                " as JSON."
+            }
+        }
+
+        section {
+            h3 { "Delete Account" }
+            p {
+                "Deleting your account revokes Fediurl's access token and removes everything stored "
+                "about you. You can log in again later to start afresh."
+            }
+            form."form-narrow"[action = uri!(crate::web::account::delete).to_string(), method = "post"] {
+                input[type = "hidden", name = "_method", value = "delete"];
+                label {
+                    input[type = "checkbox", id = "confirm", name = "confirm", value = "true"];
+                    " I understand that this can't be undone"
+                }
+                div.buttons {
+                    input[type = "submit", name = "submit", value = "Delete my account"];
+                }
+            }
+        }
+    }
+}
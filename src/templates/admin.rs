@@ -0,0 +1,226 @@
+use super::{format_optional_time, format_time};
+use crate::http::circuit::InstanceStatus;
+use crate::models::failure::Failure;
+use crate::models::health::HealthEvent;
+use crate::models::instance::InstanceSummary;
+use crate::models::user::UserSummary;
+use crate::models::Ban;
+
+markup::define! {
+    Dashboard<'a>(
+        instances: &'a [InstanceSummary],
+        statuses: &'a [InstanceStatus],
+        users: &'a [UserSummary],
+        banned_users: &'a [UserSummary],
+        failures: &'a [Failure],
+        health: &'a [HealthEvent]
+    ) {
+        section.admin {
+            h3 { "Instances" }
+            table {
+                thead {
+                    tr {
+                        th { "Domain" }
+                        th { "Software" }
+                        th { "Users" }
+                        th { "Last log in" }
+                        th { "Errors" }
+                        th { "Health" }
+                        th { "Status" }
+                        th { "Actions" }
+                    }
+                }
+                tbody {
+                    @for (instance, status) in instances.iter().zip(statuses.iter()) {
+                        tr {
+                            td { @instance.domain }
+                            td { @instance.software.as_deref().unwrap_or("unknown") }
+                            td { @instance.user_count }
+                            td { @format_optional_time(instance.last_login) }
+                            td { @instance.failure_count }
+                            td { @HealthStatus { status: *status } }
+                            td { @BanStatus { ban: instance.active_ban() } }
+                            td {
+                                @BanForm { action: uri!(crate::web::admin::ban_instance(id = instance.id.value())).to_string() }
+                                @ActionButton { action: uri!(crate::web::admin::unban_instance(id = instance.id.value())).to_string(), method: "post", label: "Unban" }
+                                @ActionButton { action: uri!(crate::web::admin::register_instance(id = instance.id.value())).to_string(), method: "post", label: "Re-register app" }
+                                @ActionButton { action: uri!(crate::web::admin::delete_instance(id = instance.id.value())).to_string(), method: "delete", label: "Delete" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        section.admin {
+            h3 { "Recent Users" }
+            @UserTable { users: *users }
+        }
+
+        section.admin {
+            h3 { "Bans" }
+            ul {
+                @for instance in instances.iter() {
+                    @if let Some(ban) = instance.active_ban() {
+                        li { @ban.to_string() }
+                    }
+                }
+                @for user in banned_users.iter() {
+                    @if let Some(ban) = user.active_ban() {
+                        li {
+                            @user.account.as_deref().unwrap_or("unknown account") " (" @user.domain ", user " @user.id.value() "): "
+                            @ban.to_string()
+                        }
+                    }
+                }
+            }
+            @if !banned_users.is_empty() {
+                @UserTable { users: *banned_users }
+            }
+        }
+
+        section.admin {
+            h3 { "Detection" }
+            p {
+                "The software has been detected for "
+                @instances.iter().filter(|instance| instance.software_detected()).count()
+                " of " @instances.len() " instances, and a working search API is known for "
+                @instances.iter().filter(|instance| instance.search_api.is_some()).count()
+                "."
+            }
+        }
+
+        section.admin {
+            h3 { "Recent Failures" }
+            table {
+                thead {
+                    tr {
+                        th { "Time" }
+                        th { "Domain" }
+                        th { "Kind" }
+                        th { "Message" }
+                    }
+                }
+                tbody {
+                    @for failure in failures.iter() {
+                        tr {
+                            td { @format_time(failure.created_at) }
+                            td { @failure.domain }
+                            td { @failure.kind }
+                            td { @failure.message }
+                        }
+                    }
+                }
+            }
+        }
+
+        section.admin {
+            h3 { "Instance Health" }
+            table {
+                thead {
+                    tr {
+                        th { "Time" }
+                        th { "Domain" }
+                        th { "Status" }
+                        th { "Reason" }
+                    }
+                }
+                tbody {
+                    @for event in health.iter() {
+                        tr {
+                            td { @format_time(event.created_at) }
+                            td { @event.domain }
+                            td { @if event.up { "Up" } else { "Down" } }
+                            td { @event.message.as_deref().unwrap_or_default() }
+                        }
+                    }
+                }
+            }
+        }
+
+        section.admin {
+            h3 { "Database" }
+            p { "Write a copy of the database to the directory set in " code { "backup_dir" } "." }
+            @ActionButton { action: uri!(crate::web::admin::backup_database).to_string(), method: "post", label: "Back up now" }
+        }
+    }
+
+    UserTable<'a>(users: &'a [UserSummary]) {
+        table {
+            thead {
+                tr {
+                    th { "ID" }
+                    th { "Account" }
+                    th { "Instance" }
+                    th { "Created" }
+                    th { "Last used" }
+                    th { "Status" }
+                    th { "Actions" }
+                }
+            }
+            tbody {
+                @for user in users.iter() {
+                    tr {
+                        td { @user.id.value() }
+                        td { @user.account.as_deref().unwrap_or("unknown") }
+                        td { @user.domain }
+                        td { @format_time(user.created_at) }
+                        td { @format_optional_time(user.last_used_at) }
+                        td { @BanStatus { ban: user.active_ban() } }
+                        td {
+                            @BanForm { action: uri!(crate::web::admin::ban_user(id = user.id.value())).to_string() }
+                            @ActionButton { action: uri!(crate::web::admin::unban_user(id = user.id.value())).to_string(), method: "post", label: "Unban" }
+                            @ActionButton { action: uri!(crate::web::admin::delete_user(id = user.id.value())).to_string(), method: "delete", label: "Delete" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    BanStatus(ban: Option<Ban>) {
+        @match ban {
+            Some(ban) => {
+                span."ban-status"[title = ban.to_string()] { "Banned" }
+            }
+            None => {
+                "Active"
+            }
+        }
+    }
+
+    HealthStatus(status: InstanceStatus) {
+        @match status {
+            InstanceStatus::Up => { "Up" }
+            InstanceStatus::Degraded { failures } => {
+                "Degraded (" @failures " failures)"
+            }
+            InstanceStatus::Down { since, retry_at } => {
+                span."ban-status"[title = format!("Retrying after {}", format_time(*retry_at))] {
+                    "Down since " @format_time(*since)
+                }
+            }
+        }
+    }
+
+    BanForm(action: String) {
+        form."form-inline"[action = action, method = "post"] {
+            input[type = "number", name = "days", min = "1", placeholder = "Days"];
+            label {
+                input[type = "checkbox", name = "permanent", value = "true"];
+                " Permanent"
+            }
+            input[type = "text", name = "reason", placeholder = "Reason"];
+            input[type = "submit", value = "Ban"];
+        }
+    }
+
+    ActionButton<'a>(action: String, method: &'a str, label: &'a str) {
+        form."form-inline"[action = action, method = "post"] {
+            @if *method != "post" {
+                input[type = "hidden", name = "_method", value = method];
+            }
+            input[type = "submit", value = label];
+        }
+    }
+}
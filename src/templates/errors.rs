@@ -0,0 +1,31 @@
+use crate::http::RequestContext;
+use crate::models::Ban;
+use crate::web::rate_limit::describe_wait;
+
+markup::define! {
+    Banned<'a>(ban: &'a Ban) {
+        p { @ban.to_string() }
+        p {
+            "If you believe this is a mistake contact the administrator of this "
+            @crate::NAME " server."
+        }
+    }
+
+    ErrorPage<'a>(description: &'a str, context: Option<&'a RequestContext>) {
+        p { @description }
+        @if let Some(context) = context {
+            p."error-context" {
+                "Request to your instance: " code { @context.to_string() }
+            }
+        }
+        p { "If the problem persists contact the administrator of this " @crate::NAME " server." }
+    }
+
+    TooManyRequests(retry_after: u64) {
+        p { "Too many requests have been made, please try again in " @describe_wait(*retry_after) "." }
+        p {
+            "Rewriting a URL makes your instance fetch content from other servers, so "
+            @crate::NAME " limits how often this can be done."
+        }
+    }
+}
@@ -1,7 +1,8 @@
+use crate::http::circuit::InstanceStatus;
 use crate::models::instance::Instance;
 
 markup::define! {
-    Home(instance: Option<Instance>) {
+    Home(instance: Option<Instance>, status: Option<InstanceStatus>) {
         section."text-center"[id="connect"] {
             h2 { "Redirect Mastodon links to your own instance" }
 
@@ -13,6 +14,14 @@ markup::define! {
 
             @if let Some(instance) = instance {
                 "You are connected to " @instance.domain
+                @if let Some(InstanceStatus::Down { since, retry_at }) = status {
+                    p.flash."flash-warning" {
+                        @instance.domain " appears to have been down since "
+                        @format!("{:02}:{:02} UTC", since.hour(), since.minute())
+                        ". Links won't be rewritten until it is back up, Fediurl will try it again after "
+                        @format!("{:02}:{:02} UTC", retry_at.hour(), retry_at.minute()) "."
+                    }
+                }
             }
         }
     }
@@ -89,6 +98,8 @@ markup::define! {
             li { "Fediurl requests the bare minimum read-only permissions to perform its function. "
             "it can't read or post to your timeline." }
             li { "There is no tracking or analytics used on the site." }
+            li { "You can see, download, and delete the data stored about you from your account "
+            "page once logged in." }
             li { "User tokens " del { "are" } " will be stored encrypted in the database (see "
                 a[href="https://github.com/wezm/fediurl/issues/11"] { "#11" } ")." }
             li {
@@ -63,8 +63,11 @@ markup::define! {
                         h1."pull-left" { a[href = uri!(crate::web::home).to_string()] { @crate::NAME } }
                         nav."text-right" {
                             ul."list-inline" {
-                                li {
-                                    @if let Some(_user) = current_user {
+                                @if let Some(_user) = current_user {
+                                    li {
+                                        a[href = uri!(crate::web::account::show).to_string()] { "Account" }
+                                    }
+                                    li {
                                         @Logout {}
                                     }
                                 }
@@ -11,6 +11,12 @@ markup::define! {
             @field_errors(&context, "instance")
             p."field-description" { "Such as 'mastodon.social'." }
 
+            label {
+                input[type="checkbox", id="domain_blocks", name="domain_blocks", value="true", checked=context.value_for("domain_blocks") == "true", tabindex=2];
+                " Don't follow links to domains I have blocked"
+            }
+            p."field-description" { "Fediurl will be granted access to read your domain blocks. Not supported by Misskey." }
+
             div.buttons {
                 input[type="submit", name="submit", value="Log in", tabindex=3];
             }
@@ -1,16 +1,24 @@
+pub mod account;
+pub mod admin;
+pub mod health;
+pub mod metrics;
+pub mod rate_limit;
 pub mod rewrite;
 pub mod session;
 mod r#static;
 
 use std::borrow::Cow;
-use std::time::Instant;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use rocket::fairing::{self, AdHoc, Fairing, Info, Kind};
 use rocket::figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment, Profile,
 };
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::outcome::IntoOutcome;
 use rocket::request::FlashMessage;
 use rocket::request::{FromRequest, Outcome};
@@ -18,28 +26,54 @@ use rocket::response::content::RawHtml;
 use rocket::serde::json::serde_json::json;
 use rocket::{Build, Data, Request, Response, Rocket};
 use rocket::{Catcher, Route, State};
-use rocket_db_pools::{sqlx, Connection, Database};
+use rocket_db_pools::{Connection, Database};
 
+use crate::backoff::Backoff;
 use crate::config::AppConfig;
-use crate::db::Db;
-use crate::templates::{Home, Layout, Nil, Privacy, Title};
+#[cfg(not(feature = "postgres"))]
+use crate::config::SqliteConfig;
+#[cfg(not(feature = "postgres"))]
+use crate::db::SqliteSettings;
+use crate::db::{Db, MIGRATOR};
+use crate::http::{HttpClient, RequestContext};
+use crate::metrics::Metrics;
+use crate::models::Ban;
+use crate::policy::{BlockedDomains, InstancePolicy, UserBlocks};
+use crate::templates::{Banned, ErrorPage, Home, Layout, Nil, Privacy, Title, TooManyRequests};
+use crate::web::metrics::RequestMetrics;
+use crate::web::rate_limit::{describe_wait, RateLimiter, RetryAfter};
+use crate::web::session::registration::CheckedCredentials;
 use crate::web::session::AuthenticatedUser;
-use crate::{html, FediurlError};
+use crate::{html, report, FediurlError};
 
-pub fn rocket() -> Rocket<Build> {
-    let figment = Figment::from(rocket::Config::default())
+/// The configuration sources for Fediurl: defaults, `Fediurl.toml`, and `FEDIURL_` environment
+/// variables
+pub fn figment() -> Figment {
+    Figment::from(rocket::Config::default())
         .merge(Serialized::defaults(AppConfig::default()))
         .merge(Toml::file("Fediurl.toml").nested())
         .merge(Env::prefixed("FEDIURL_").global())
-        .select(Profile::from_env_or("FEDIURL_PROFILE", "default"));
+        .select(Profile::from_env_or("FEDIURL_PROFILE", "default"))
+}
 
-    rocket::custom(figment)
+pub fn rocket() -> Rocket<Build> {
+    rocket::custom(figment())
         .attach(stage())
         .attach(RequestTimer(None))
+        .attach(RequestMetrics)
         .attach(AdHoc::config::<AppConfig>())
+        .manage(RateLimiter::default())
+        .manage(Backoff::default())
+        .manage(UserBlocks::default())
+        .manage(CheckedCredentials::default())
+        .manage(Metrics::default())
         .mount("/", routes())
         .mount("/", session::routes())
         .mount("/", rewrite::routes())
+        .mount("/", admin::routes())
+        .mount("/", account::routes())
+        .mount("/", metrics::routes())
+        .mount("/", health::routes())
         .mount("/", r#static::routes())
         .register("/", catchers())
 }
@@ -49,13 +83,21 @@ pub fn routes() -> Vec<Route> {
 }
 
 pub fn catchers() -> Vec<Catcher> {
-    catchers![not_found, payload_too_large, internal_server_error]
+    catchers![
+        forbidden,
+        not_found,
+        payload_too_large,
+        too_many_requests,
+        internal_server_error,
+        default_catcher
+    ]
 }
 
 #[get("/")]
 pub(crate) async fn home<'f>(
     mut db: Connection<Db>,
     config: &State<AppConfig>,
+    client: &State<HttpClient>,
     flash: Option<FlashMessage<'f>>,
     current_user: Option<AuthenticatedUser>,
 ) -> Result<RawHtml<String>, FediurlError> {
@@ -63,13 +105,16 @@ pub(crate) async fn home<'f>(
         Some(ref user) => Some(user.instance(&mut *db).await?),
         None => None,
     };
+    let status = instance
+        .as_ref()
+        .map(|instance| client.status(&instance.domain));
     let page = Layout {
         config: config,
         title: Title::head("Home"),
         flash: flash.as_ref(),
         current_user: current_user.as_ref(),
         head: Nil {},
-        body: Home { instance },
+        body: Home { instance, status },
     };
     Ok(html(page))
 }
@@ -91,10 +136,63 @@ pub(crate) async fn privacy<'f>(
     Ok(html(page))
 }
 
+/// Request local state used to pass the details of a ban from a guard to the 403 catcher
+pub(crate) struct BanNotice(pub Option<Ban>);
+
+#[catch(403)]
+fn forbidden(req: &Request<'_>) -> (Status, (ContentType, Cow<'static, str>)) {
+    match req.local_cache(|| BanNotice(None)) {
+        BanNotice(Some(ban)) => {
+            let (status, (content_type, body)) = banned(req, ban);
+            (status, (content_type, body.into()))
+        }
+        BanNotice(None) => (
+            Status::Forbidden,
+            (ContentType::Plain, Cow::from("403 Forbidden")),
+        ),
+    }
+}
+
+/// Render the page or JSON shown to a banned user or user of a banned instance
+pub(crate) fn banned(req: &Request<'_>, ban: &Ban) -> (Status, (ContentType, String)) {
+    let preferred = req.accept().map(|a| a.preferred());
+    if preferred.map_or(false, |a| a.is_json()) {
+        let json = json!({
+             "error": {
+                "code": 403,
+                "reason": "Forbidden",
+                "description": ban.to_string(),
+                "until": ban.until.map(|until| until.unix_timestamp()),
+              }
+        })
+        .to_string();
+
+        (Status::Forbidden, (ContentType::JSON, json))
+    } else {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = req.rocket().state::<AppConfig>().unwrap();
+        let page = Layout {
+            config,
+            title: Title::head_and_body("Banned"),
+            flash: None,
+            current_user: None,
+            head: Nil {},
+            body: Banned { ban },
+        };
+        (Status::Forbidden, (ContentType::HTML, page.to_string()))
+    }
+}
+
+/// Catcher for missing pages, and things the user's instance couldn't find
 #[catch(404)]
-fn not_found() -> RawHtml<&'static str> {
+fn not_found(status: Status, req: &Request<'_>) -> (Status, (ContentType, Cow<'static, str>)) {
+    if req.local_cache(ErrorNotice::default).description.is_some() {
+        let (status, (content_type, body)) = error_notice(status, req);
+        return (status, (content_type, body.into()));
+    }
+
     const BODY: &str = include_str!("templates/404.html");
-    RawHtml(BODY)
+    (status, (ContentType::HTML, Cow::from(BODY)))
 }
 
 #[catch(413)]
@@ -122,28 +220,168 @@ pub fn payload_too_large<'r>(
     }
 }
 
+/// A response to a rate limited request, with a `Retry-After` header
+#[derive(Responder)]
+#[response(status = 429)]
+pub(crate) struct TooManyRequestsResponse {
+    body: (ContentType, String),
+    retry_after: Header<'static>,
+}
+
+#[catch(429)]
+fn too_many_requests(req: &Request<'_>) -> TooManyRequestsResponse {
+    let retry_after = match req.local_cache(|| RetryAfter(None)) {
+        RetryAfter(Some(retry_after)) => retry_after.as_secs().max(1),
+        RetryAfter(None) => 60,
+    };
+    let description = format!(
+        "Too many requests, please try again in {}.",
+        describe_wait(retry_after)
+    );
+
+    let preferred = req.accept().map(|a| a.preferred());
+    let body = if preferred.map_or(false, |a| a.is_json()) {
+        let json = json!({
+             "error": {
+                "code": 429,
+                "reason": "Too Many Requests",
+                "description": description,
+                "retry_after": retry_after,
+              }
+        })
+        .to_string();
+        (ContentType::JSON, json)
+    } else {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = req.rocket().state::<AppConfig>().unwrap();
+        let page = Layout {
+            config,
+            title: Title::head_and_body("Too Many Requests"),
+            flash: None,
+            current_user: None,
+            head: Nil {},
+            body: TooManyRequests { retry_after },
+        };
+        (ContentType::HTML, page.to_string())
+    };
+
+    TooManyRequestsResponse {
+        body,
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    }
+}
+
 #[catch(500)]
 fn internal_server_error() -> RawHtml<&'static str> {
     const BODY: &str = include_str!("templates/500.html");
     RawHtml(BODY)
 }
 
-pub fn stage() -> AdHoc {
-    // TODO: Set SQLite options:
-    // synchronous = NORMAL, should be enough when WAL is used. Rocket/SQLx defaults to FULL
-    // https://api.rocket.rs/v0.5-rc/rocket_db_pools/index.html#driver-defaults
+/// Request local state used to pass the details of an error to the default catcher
+#[derive(Default)]
+pub(crate) struct ErrorNotice {
+    pub description: Option<String>,
+    /// The request to the instance that failed, if the error came from one
+    pub context: Option<RequestContext>,
+}
+
+/// Catcher for other errors, mostly those caused by the user's instance
+#[catch(default)]
+fn default_catcher(status: Status, req: &Request<'_>) -> (Status, (ContentType, String)) {
+    error_notice(status, req)
+}
+
+/// Render the page or JSON describing an error, with the details from `ErrorNotice` if set
+fn error_notice(status: Status, req: &Request<'_>) -> (Status, (ContentType, String)) {
+    let reason = status.reason_lossy();
+    let notice = req.local_cache(ErrorNotice::default);
+    let description = notice.description.as_deref().unwrap_or(reason);
 
+    let preferred = req.accept().map(|a| a.preferred());
+    if preferred.map_or(false, |a| a.is_json()) {
+        let json = json!({
+             "error": {
+                "code": status.code,
+                "reason": reason,
+                "description": description,
+                "context": notice.context,
+              }
+        })
+        .to_string();
+        (status, (ContentType::JSON, json))
+    } else {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = req.rocket().state::<AppConfig>().unwrap();
+        let page = Layout {
+            config,
+            title: Title::head_and_body(reason),
+            flash: None,
+            current_user: None,
+            head: Nil {},
+            body: ErrorPage {
+                description,
+                context: notice.context.as_ref(),
+            },
+        };
+        (status, (ContentType::HTML, page.to_string()))
+    }
+}
+
+pub fn stage() -> AdHoc {
     AdHoc::on_ignite("SQLx Stage", |rocket| async {
+        let rocket = rocket.attach(Db::init());
+        #[cfg(not(feature = "postgres"))]
+        let rocket = rocket.attach(AdHoc::try_on_ignite(
+            "SQLite settings",
+            check_sqlite_settings,
+        ));
         rocket
-            .attach(Db::init())
             .attach(AdHoc::try_on_ignite("SQLx Migrations", run_migrations))
-            .attach(AdHoc::try_on_ignite("Load config", init_sentry))
+            .attach(report::stage())
+            .attach(AdHoc::try_on_ignite("Instance policy", init_policy))
+            .attach(AdHoc::try_on_ignite("HTTP client", init_http_client))
     })
 }
 
+/// Log the settings in effect on database connections, warning if they aren't as configured
+#[cfg(not(feature = "postgres"))]
+async fn check_sqlite_settings(rocket: Rocket<Build>) -> fairing::Result {
+    let Some(db) = Db::fetch(&rocket) else {
+        return Err(rocket);
+    };
+    let config = match rocket
+        .figment()
+        .extract_inner::<SqliteConfig>("databases.fediurl_db")
+    {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid database config: {}", err);
+            return Err(rocket);
+        }
+    };
+
+    let settings = match db.pool().acquire().await {
+        Ok(mut conn) => SqliteSettings::query(&mut conn).await,
+        Err(err) => Err(err),
+    };
+    match settings {
+        Ok(settings) => {
+            info!("SQLite settings: {}", settings);
+            for difference in settings.differences(&config) {
+                warn!("SQLite {}", difference);
+            }
+            Ok(rocket)
+        }
+        Err(err) => {
+            error!("Failed to query SQLite settings: {}", err);
+            Err(rocket)
+        }
+    }
+}
+
 async fn run_migrations(rocket: Rocket<Build>) -> fairing::Result {
     match Db::fetch(&rocket) {
-        Some(db) => match sqlx::migrate!("./migrations").run(&**db).await {
+        Some(db) => match MIGRATOR.run(db.pool()).await {
             Ok(_) => Ok(rocket),
             Err(e) => {
                 error!("Failed to initialize SQLx database: {}", e);
@@ -154,25 +392,33 @@ async fn run_migrations(rocket: Rocket<Build>) -> fairing::Result {
     }
 }
 
-pub async fn init_sentry(mut rocket: Rocket<Build>) -> fairing::Result {
+async fn init_policy(rocket: Rocket<Build>) -> fairing::Result {
+    // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
     let config = rocket.state::<AppConfig>().unwrap();
 
-    if let Some(dsn) = config.sentry_dsn.as_deref() {
-        let guard = sentry::init((
-            dsn,
-            sentry::ClientOptions {
-                release: sentry::release_name!(),
-                attach_stacktrace: true,
-                ..Default::default()
-            },
-        ));
-        info!("Sentry initialised");
-        rocket = rocket.manage(guard)
-    } else {
-        info!("No Sentry DSN")
+    let policy = InstancePolicy::load(&config.instance_policy)
+        .and_then(|policy| Ok((policy, BlockedDomains::load(config)?)));
+    match policy {
+        Ok((policy, blocked)) => Ok(rocket.manage(policy).manage(blocked)),
+        Err(err) => {
+            error!("Failed to load instance policy: {}", err);
+            Err(rocket)
+        }
     }
+}
+
+async fn init_http_client(rocket: Rocket<Build>) -> fairing::Result {
+    // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+    let config = rocket.state::<AppConfig>().unwrap();
 
-    Ok(rocket)
+    let db = Db::fetch(&rocket).map(|db| db.pool().clone());
+    match HttpClient::new(&config.http, db) {
+        Ok(client) => Ok(rocket.manage(client)),
+        Err(err) => {
+            error!("Failed to build HTTP client: {}", err);
+            Err(rocket)
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -204,8 +450,82 @@ impl Fairing for RequestTimer {
                 let ms = us / 1000;
                 response.set_raw_header("X-Response-Time", format!("{} ms", ms));
             }
+
+            let timing = request.local_cache(ServerTiming::default);
+            response.set_raw_header("Server-Timing", timing.header_value(duration));
+        }
+    }
+}
+
+/// Durations of the parts of handling a request, sent in the `Server-Timing` header
+///
+/// Handlers get this as a request guard and record spans with `ServerTiming::time`. Spans with
+/// the same name are added together.
+#[derive(Default)]
+pub struct ServerTiming(Mutex<Vec<TimingSpan>>);
+
+struct TimingSpan {
+    name: &'static str,
+    description: &'static str,
+    duration: Duration,
+}
+
+impl ServerTiming {
+    /// Record that `duration` was spent on `name`
+    pub fn record(&self, name: &'static str, description: &'static str, duration: Duration) {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut spans = self.0.lock().unwrap();
+        match spans.iter_mut().find(|span| span.name == name) {
+            Some(span) => span.duration += duration,
+            None => spans.push(TimingSpan {
+                name,
+                description,
+                duration,
+            }),
         }
     }
+
+    /// Await `fut`, recording the time taken as `name`
+    pub async fn time<F: Future>(
+        &self,
+        name: &'static str,
+        description: &'static str,
+        fut: F,
+    ) -> F::Output {
+        let start = Instant::now();
+        let output = fut.await;
+        self.record(name, description, start.elapsed());
+        output
+    }
+
+    /// The value of the `Server-Timing` header, with the recorded spans and the `total`
+    fn header_value(&self, total: Duration) -> String {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let spans = self.0.lock().unwrap();
+        let mut value = String::new();
+        for span in spans.iter() {
+            // NOTE(unwrap): writing to a String can't fail
+            write!(
+                value,
+                "{};desc=\"{}\";dur={:.1}, ",
+                span.name,
+                span.description,
+                span.duration.as_secs_f64() * 1000.0
+            )
+            .unwrap();
+        }
+        write!(value, "total;dur={:.1}", total.as_secs_f64() * 1000.0).unwrap();
+        value
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r ServerTiming {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(ServerTiming::default))
+    }
 }
 
 /// Request guard used to retrieve the start time of a request.
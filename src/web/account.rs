@@ -0,0 +1,183 @@
+//! The account page, where users can see, download, and delete the data stored about them.
+
+use rocket::form::Form;
+use rocket::http::{Cookie, CookieJar, Header};
+use rocket::request::FlashMessage;
+use rocket::response::content::RawHtml;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{Route, State};
+use rocket_db_pools::Connection;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::AppConfig;
+use crate::db::Db;
+use crate::http::HttpClient;
+use crate::models::failure::Failure;
+use crate::models::user::User;
+use crate::templates::{self, Layout, Nil, Title};
+use crate::web::session::{revoke_token, AuthenticatedUser, FEDIURL_SESSION};
+use crate::{html, web, FediurlError};
+
+#[derive(FromForm)]
+struct DeleteForm {
+    /// The user ticked the box confirming they want their account deleted
+    confirm: bool,
+}
+
+/// The data stored about a user, as downloaded from the account page
+///
+/// The access tokens themselves are left out, they are only ever used to make requests on the
+/// user's behalf.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AccountData {
+    account: Option<String>,
+    instance: String,
+    /// A user for the account may have been stored for each time they logged in
+    users: Vec<UserData>,
+    failures: Vec<FailureData>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct UserData {
+    scopes: Option<String>,
+    logged_in_at: String,
+    updated_at: String,
+    last_used_at: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct FailureData {
+    kind: String,
+    message: String,
+    created_at: String,
+}
+
+/// A JSON response that browsers save as a file
+#[derive(Responder)]
+struct Download(Json<AccountData>, Header<'static>);
+
+pub fn routes() -> Vec<Route> {
+    routes![show, show_redirect, export, delete]
+}
+
+#[get("/account")]
+pub async fn show(
+    user: AuthenticatedUser,
+    mut db: Connection<Db>,
+    config: &State<AppConfig>,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<RawHtml<String>, FediurlError> {
+    let instance = user.instance(&mut *db).await?;
+    let failures = Failure::for_account(&mut *db, &user).await?;
+
+    let page = Layout {
+        config: config,
+        title: Title::head_and_body("Account"),
+        flash: flash.as_ref(),
+        current_user: Some(&user),
+        head: Nil {},
+        body: templates::account::Account {
+            user: &user,
+            instance: &instance,
+            failures: &failures,
+        },
+    };
+    Ok(html(page))
+}
+
+#[get("/account", rank = 2)]
+fn show_redirect() -> Redirect {
+    // Not logged in
+    Redirect::to(uri!(web::session::new))
+}
+
+#[get("/account/export")]
+async fn export(user: AuthenticatedUser, mut db: Connection<Db>) -> Result<Download, FediurlError> {
+    let instance = user.instance(&mut *db).await?;
+    let users = User::same_account(&mut *db, &user).await?;
+    let failures = Failure::for_account(&mut *db, &user)
+        .await?
+        .into_iter()
+        .map(|failure| FailureData {
+            kind: failure.kind,
+            message: failure.message,
+            created_at: format_rfc3339(failure.created_at),
+        })
+        .collect();
+    let users = users
+        .into_iter()
+        .map(|user| UserData {
+            scopes: user.scopes,
+            logged_in_at: format_rfc3339(user.created_at),
+            updated_at: format_rfc3339(user.updated_at),
+            last_used_at: user.last_used_at.map(format_rfc3339),
+        })
+        .collect();
+
+    let data = AccountData {
+        account: user.account.clone(),
+        instance: instance.domain,
+        users,
+        failures,
+    };
+    Ok(Download(
+        Json(data),
+        Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"fediurl-data.json\"",
+        ),
+    ))
+}
+
+#[delete("/account", data = "<form>")]
+async fn delete(
+    user: AuthenticatedUser,
+    mut db: Connection<Db>,
+    client: &State<HttpClient>,
+    cookies: &CookieJar<'_>,
+    form: Form<DeleteForm>,
+) -> Result<Flash<Redirect>, FediurlError> {
+    if !form.confirm {
+        return Ok(Flash::error(
+            Redirect::to(uri!(show)),
+            "Tick the box to confirm that you want to delete your account",
+        ));
+    }
+
+    // Revoke the tokens first, the instance is still told about them if deleting the users fails
+    let instance = user.instance(&mut *db).await?;
+    let users = User::same_account(&mut *db, &user).await?;
+    let mut revoked = true;
+    for user in &users {
+        revoked &= revoke_token(client, &instance, &user.access_token)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("unable to revoke token for user {}: {}", user.id, err);
+                false
+            });
+    }
+
+    let ids = users.iter().map(|user| user.id).collect::<Vec<_>>();
+    User::delete_all(&mut *db, &ids).await?;
+    cookies.remove_private(Cookie::named(FEDIURL_SESSION));
+
+    let message = if revoked {
+        "Your account has been deleted"
+    } else {
+        "Your account has been deleted. Remove Fediurl from the authorised applications in your \
+         instance's settings to revoke its access."
+    };
+    Ok(Flash::success(Redirect::to(uri!(web::home)), message))
+}
+
+fn format_rfc3339(time: OffsetDateTime) -> String {
+    // NOTE(unwrap): formatting only fails for years that can't be represented in RFC 3339, which
+    // timestamps stored by Fediurl are never in
+    time.format(&Rfc3339).unwrap()
+}
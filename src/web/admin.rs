@@ -0,0 +1,331 @@
+//! Administration interface, available to the accounts listed in the `admins` config.
+
+use std::ops::Deref;
+
+use rocket::form::Form;
+use rocket::http::uri::Host;
+use rocket::outcome::try_outcome;
+use rocket::request::{FlashMessage, FromRequest, Outcome, Request};
+use rocket::response::content::RawHtml;
+use rocket::response::{Flash, Redirect};
+use rocket::{Route, State};
+use rocket_db_pools::Connection;
+use time::{Duration, OffsetDateTime};
+
+use crate::backup;
+use crate::config::AppConfig;
+use crate::db::Db;
+use crate::http::HttpClient;
+use crate::models::failure::Failure;
+use crate::models::health::HealthEvent;
+use crate::models::instance::{Instance, InstanceId};
+use crate::models::user::{User, UserId};
+use crate::templates::{self, Layout, Nil, Title};
+use crate::web::session::registration::{reregister_app, CheckedCredentials};
+use crate::web::session::{auth_redirect_uri, AuthenticatedUser, AuthenticatedUserError};
+use crate::web::XForwardedProto;
+use crate::{html, FediurlError};
+
+const RECENT_USERS: i64 = 100;
+const RECENT_FAILURES: i64 = 50;
+const RECENT_HEALTH_EVENTS: i64 = 50;
+
+/// A logged in user that is listed as an admin
+pub struct AdminUser(AuthenticatedUser);
+
+#[derive(FromForm)]
+struct BanForm<'v> {
+    /// Length of the ban in days, ignored for a permanent ban
+    ///
+    /// This is parsed by `until` rather than as a number field, so that a mistyped length is
+    /// reported instead of being treated as missing.
+    days: &'v str,
+    permanent: bool,
+    reason: &'v str,
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        dashboard,
+        ban_instance,
+        unban_instance,
+        delete_instance,
+        register_instance,
+        ban_user,
+        unban_user,
+        delete_user,
+        backup_database,
+    ]
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = AuthenticatedUserError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(request.guard::<AuthenticatedUser>().await);
+        let is_admin = match (
+            request.rocket().state::<AppConfig>(),
+            user.account.as_deref(),
+        ) {
+            (Some(config), Some(account)) => config.is_admin(account),
+            _ => false,
+        };
+
+        // Forward non-admins so that the admin interface appears not to exist
+        if is_admin {
+            Outcome::Success(AdminUser(user))
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+impl Deref for AdminUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[get("/admin")]
+async fn dashboard(
+    admin: AdminUser,
+    mut db: Connection<Db>,
+    config: &State<AppConfig>,
+    client: &State<HttpClient>,
+    flash: Option<FlashMessage<'_>>,
+) -> Result<RawHtml<String>, FediurlError> {
+    let instances = Instance::summaries(&mut *db).await?;
+    let statuses = instances
+        .iter()
+        .map(|instance| client.status(&instance.domain))
+        .collect::<Vec<_>>();
+    let users = User::recent_summaries(&mut *db, RECENT_USERS).await?;
+    let banned_users = User::banned_summaries(&mut *db).await?;
+    let failures = Failure::recent(&mut *db, RECENT_FAILURES).await?;
+    let health = HealthEvent::recent(&mut *db, RECENT_HEALTH_EVENTS).await?;
+
+    let page = Layout {
+        config: config,
+        title: Title::head_and_body("Admin"),
+        flash: flash.as_ref(),
+        current_user: Some(&admin.0),
+        head: Nil {},
+        body: templates::admin::Dashboard {
+            instances: &instances,
+            statuses: &statuses,
+            users: &users,
+            banned_users: &banned_users,
+            failures: &failures,
+            health: &health,
+        },
+    };
+    Ok(html(page))
+}
+
+#[post("/admin/instances/<id>/ban", data = "<form>")]
+async fn ban_instance(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+    form: Form<BanForm<'_>>,
+) -> Result<Flash<Redirect>, FediurlError> {
+    let until = match form.until() {
+        Ok(until) => until,
+        Err(message) => return Ok(Flash::error(Redirect::to(uri!(dashboard)), message)),
+    };
+    Instance::ban(&mut *db, InstanceId::from(id), until, form.reason()).await?;
+    Ok(Flash::success(
+        Redirect::to(uri!(dashboard)),
+        "Instance banned",
+    ))
+}
+
+#[post("/admin/instances/<id>/unban")]
+async fn unban_instance(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+) -> Result<Flash<Redirect>, FediurlError> {
+    Instance::unban(&mut *db, InstanceId::from(id)).await?;
+    Ok(Flash::success(
+        Redirect::to(uri!(dashboard)),
+        "Instance unbanned",
+    ))
+}
+
+#[delete("/admin/instances/<id>")]
+async fn delete_instance(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+) -> Result<Flash<Redirect>, FediurlError> {
+    Instance::delete(&mut *db, InstanceId::from(id)).await?;
+    Ok(Flash::success(
+        Redirect::to(uri!(dashboard)),
+        "Instance and its users deleted",
+    ))
+}
+
+/// Register a new OAuth application with the instance, replacing the existing credentials
+#[post("/admin/instances/<id>/register")]
+#[allow(clippy::too_many_arguments)]
+async fn register_instance(
+    _admin: AdminUser,
+    host: &Host<'_>,
+    proto: Option<XForwardedProto<'_>>,
+    mut db: Connection<Db>,
+    config: &State<AppConfig>,
+    client: &State<HttpClient>,
+    checked: &State<CheckedCredentials>,
+    id: i64,
+) -> Result<Flash<Redirect>, FediurlError> {
+    let instance = Instance::from_id(&mut *db, InstanceId::from(id)).await?;
+    if instance
+        .software()
+        .map_or(false, |software| software.is_misskey())
+    {
+        return Ok(Flash::error(
+            Redirect::to(uri!(dashboard)),
+            format!("{} uses MiAuth, it has no app to register", instance.domain),
+        ));
+    }
+
+    let domain = instance.domain.clone();
+    let redirect_uri = auth_redirect_uri(host, &proto, config, &domain);
+    match reregister_app(&mut db, client, checked, instance, &redirect_uri).await {
+        Ok(_) => Ok(Flash::success(
+            Redirect::to(uri!(dashboard)),
+            format!("Registered a new app with {}", domain),
+        )),
+        Err(err) => Ok(Flash::error(
+            Redirect::to(uri!(dashboard)),
+            format!("Unable to register with {}: {}", domain, err),
+        )),
+    }
+}
+
+#[post("/admin/users/<id>/ban", data = "<form>")]
+async fn ban_user(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+    form: Form<BanForm<'_>>,
+) -> Result<Flash<Redirect>, FediurlError> {
+    let until = match form.until() {
+        Ok(until) => until,
+        Err(message) => return Ok(Flash::error(Redirect::to(uri!(dashboard)), message)),
+    };
+    User::ban(&mut *db, UserId::from(id), until, form.reason()).await?;
+    Ok(Flash::success(Redirect::to(uri!(dashboard)), "User banned"))
+}
+
+#[post("/admin/users/<id>/unban")]
+async fn unban_user(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+) -> Result<Flash<Redirect>, FediurlError> {
+    User::unban(&mut *db, UserId::from(id)).await?;
+    Ok(Flash::success(
+        Redirect::to(uri!(dashboard)),
+        "User unbanned",
+    ))
+}
+
+#[delete("/admin/users/<id>")]
+async fn delete_user(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    id: i64,
+) -> Result<Flash<Redirect>, FediurlError> {
+    User::delete(&mut *db, UserId::from(id)).await?;
+    Ok(Flash::success(
+        Redirect::to(uri!(dashboard)),
+        "User deleted",
+    ))
+}
+
+#[post("/admin/backup")]
+async fn backup_database(
+    _admin: AdminUser,
+    mut db: Connection<Db>,
+    config: &State<AppConfig>,
+) -> Flash<Redirect> {
+    let Some(dir) = config.backup_dir.as_deref() else {
+        return Flash::error(
+            Redirect::to(uri!(dashboard)),
+            "Set backup_dir in the config to enable backups",
+        );
+    };
+
+    let path = dir.join(backup::backup_file_name(OffsetDateTime::now_utc()));
+    match backup::backup(&mut *db, &path).await {
+        Ok(()) => Flash::success(
+            Redirect::to(uri!(dashboard)),
+            format!("Database backed up to {}", path.display()),
+        ),
+        Err(err) => {
+            error!("Backup to {} failed: {}", path.display(), err);
+            Flash::error(
+                Redirect::to(uri!(dashboard)),
+                format!("Backup failed: {}", err),
+            )
+        }
+    }
+}
+
+impl BanForm<'_> {
+    /// When the ban ends, `None` for a permanent ban, or a message if the length isn't valid
+    fn until(&self) -> Result<Option<OffsetDateTime>, String> {
+        if self.permanent {
+            return Ok(None);
+        }
+        match self.days.trim().parse::<u32>() {
+            Ok(days) if days > 0 => Ok(Some(
+                OffsetDateTime::now_utc() + Duration::days(days.into()),
+            )),
+            _ => Err(format!(
+                "\"{}\" is not a number of days, tick \"Permanent\" for a ban that doesn't end",
+                self.days
+            )),
+        }
+    }
+
+    fn reason(&self) -> Option<&str> {
+        let reason = self.reason.trim();
+        (!reason.is_empty()).then_some(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form(days: &str, permanent: bool) -> BanForm<'_> {
+        BanForm {
+            days,
+            permanent,
+            reason: "",
+        }
+    }
+
+    #[test]
+    fn ban_length() {
+        let until = form(" 7 ", false).until().unwrap().unwrap();
+        let expected = OffsetDateTime::now_utc() + Duration::days(7);
+        assert!((expected - until).abs() < Duration::minutes(1));
+
+        assert_eq!(form("", true).until(), Ok(None));
+        assert_eq!(form("7d", true).until(), Ok(None));
+    }
+
+    #[test]
+    fn invalid_ban_length() {
+        for days in ["", "7d", "-3", "0", "seven"] {
+            assert!(form(days, false).until().is_err(), "{:?}", days);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Endpoints for load balancers and service managers to check on Fediurl.
+
+use rocket::http::Status;
+use rocket::serde::json::serde_json::{json, Value};
+use rocket::serde::json::Json;
+use rocket::{Config, Route, State};
+
+use crate::config::{self, AppConfig};
+use crate::db::{self, Db};
+
+pub fn routes() -> Vec<Route> {
+    routes![healthz, readyz]
+}
+
+/// The process is up and handling requests
+#[get("/healthz")]
+fn healthz() -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "revision": config::git_revision(),
+    }))
+}
+
+/// Fediurl is able to serve requests: the database is reachable and up to date, and the config
+/// is usable
+#[get("/readyz")]
+async fn readyz(
+    db: &Db,
+    app_config: &State<AppConfig>,
+    rocket_config: &Config,
+) -> (Status, Json<Value>) {
+    // Database errors are logged rather than included, as this response is public
+    let (database, migrations) = match db.pool().acquire().await {
+        Ok(mut conn) => {
+            let database = match sqlx::query("SELECT 1").execute(&mut *conn).await {
+                Ok(_) => component(Ok(())),
+                Err(err) => {
+                    warn!("readiness check query failed: {}", err);
+                    component(Err("database query failed".to_string()))
+                }
+            };
+            let migrations = match db::pending_migrations(&mut conn).await {
+                Ok(0) => component(Ok(())),
+                Ok(pending) => component(Err(format!("{} pending migration(s)", pending))),
+                Err(err) => {
+                    warn!("readiness check of migrations failed: {}", err);
+                    component(Err("unable to check migrations".to_string()))
+                }
+            };
+            (database, migrations)
+        }
+        Err(err) => {
+            warn!("readiness check unable to connect to database: {}", err);
+            (
+                component(Err("database is unreachable".to_string())),
+                component(Err("database is unreachable".to_string())),
+            )
+        }
+    };
+
+    let config_status = if app_config.hosts.is_empty() {
+        component(Err("hosts is empty".to_string()))
+    } else if rocket_config.secret_key.is_zero() {
+        component(Err("secret_key is not set".to_string()))
+    } else {
+        component(Ok(()))
+    };
+
+    let ready = [&database, &migrations, &config_status]
+        .iter()
+        .all(|component| component["status"] == "ok");
+    let (status, description) = if ready {
+        (Status::Ok, "ok")
+    } else {
+        (Status::ServiceUnavailable, "unavailable")
+    };
+
+    let json = json!({
+        "status": description,
+        "revision": config::git_revision(),
+        "components": {
+            "database": database,
+            "migrations": migrations,
+            "config": config_status,
+        },
+    });
+    (status, Json(json))
+}
+
+/// The JSON status of a component of Fediurl
+fn component(res: Result<(), String>) -> Value {
+    match res {
+        Ok(()) => json!({ "status": "ok" }),
+        Err(message) => json!({ "status": "error", "message": message }),
+    }
+}
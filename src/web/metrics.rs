@@ -0,0 +1,94 @@
+//! The `/metrics` endpoint and the fairing that records metrics for every request.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Response, Route, State};
+
+use crate::config::AppConfig;
+use crate::db::Db;
+use crate::metrics::Metrics;
+use crate::web::RequestTimer;
+
+pub fn routes() -> Vec<Route> {
+    routes![metrics]
+}
+
+/// Request guard that only succeeds if metrics are enabled and the request has the configured
+/// bearer token, if any
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // NOTE(unwrap): AppConfig is always managed, it is added by the config fairing
+        let config = &request.rocket().state::<AppConfig>().unwrap().metrics;
+        if !config.enabled {
+            return Outcome::Forward(());
+        }
+
+        let Some(token) = config.token.as_deref() else {
+            return Outcome::Success(MetricsAccess);
+        };
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if bearer == Some(token) {
+            Outcome::Success(MetricsAccess)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[get("/metrics")]
+fn metrics(
+    _access: MetricsAccess,
+    metrics: &State<Metrics>,
+    db: &Db,
+) -> Result<(ContentType, String), Status> {
+    match metrics.render(db.pool()) {
+        Ok(body) => Ok((ContentType::Plain, body)),
+        Err(err) => {
+            error!("unable to render metrics: {}", err);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Records the count and duration of requests, by route
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let (Some(metrics), RequestTimer(Some(start))) = (
+            request.rocket().state::<Metrics>(),
+            request.local_cache(|| RequestTimer(None)),
+        ) else {
+            return;
+        };
+
+        // Use the route name rather than the path, so that the number of labels is bounded
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        metrics.observe_request(
+            route,
+            request.method().as_str(),
+            response.status().code,
+            start.elapsed(),
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Token bucket rate limiting of rewrites (per user and per instance) and log ins (per client IP).
+//!
+//! Every rewrite makes the user's instance resolve, and potentially fetch, remote content so
+//! these are limited to avoid Fediurl being used to hammer other instances.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::config::{AppConfig, RateLimit};
+use crate::models::instance::InstanceId;
+use crate::models::user::UserId;
+use crate::web::session::{AuthenticatedUser, AuthenticatedUserError};
+
+/// Number of buckets above which full buckets are discarded
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Tracks the remaining requests of each user, instance and client IP
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<RateLimitKey, Bucket>>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum RateLimitKey {
+    User(UserId),
+    Instance(InstanceId),
+    Login(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    capacity: f64,
+    /// Tokens added per second
+    rate: f64,
+}
+
+/// Request local record of how long a rate limited client needs to wait, used by the 429 catcher
+pub(crate) struct RetryAfter(pub Option<Duration>);
+
+/// A logged in user that is within the rewrite rate limits of themselves and their instance
+pub struct LimitedUser(AuthenticatedUser);
+
+/// A log in attempt from a client that is within the log in rate limit
+///
+/// Clients are identified by Rocket's `client_ip`, which is read from the `ip_header` config
+/// (`X-Real-IP` by default) if present. Behind a reverse proxy the proxy must set that header,
+/// replacing any value sent by the client, otherwise all log ins share the proxy's bucket.
+/// Without a proxy `ip_header` should be set to `false`, so clients can't pick their own IP.
+pub struct LoginLimit;
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    User(AuthenticatedUserError),
+    /// The client IP could not be determined
+    UnknownClient,
+    Exceeded,
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket for each key, or return how long until all have one available
+    ///
+    /// Tokens are only taken if every bucket has one, so a request that is rejected by one limit
+    /// doesn't count against the others.
+    fn check(&self, limits: &[(RateLimitKey, &RateLimit)]) -> Result<(), Duration> {
+        let limits = limits
+            .iter()
+            .filter(|(_, limit)| limit.burst != 0 && limit.per_minute != 0)
+            .collect::<Vec<_>>();
+        if limits.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.refilled(now) < bucket.capacity);
+        }
+
+        let mut wait = None;
+        for (key, limit) in &limits {
+            let capacity = f64::from(limit.burst);
+            let bucket = buckets.entry(*key).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                capacity,
+                rate: f64::from(limit.per_minute) / 60.,
+            });
+            bucket.tokens = bucket.refilled(now);
+            bucket.updated = now;
+
+            if bucket.tokens < 1. {
+                let bucket_wait = Duration::from_secs_f64((1. - bucket.tokens) / bucket.rate);
+                wait = wait.max(Some(bucket_wait));
+            }
+        }
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        for (key, _) in &limits {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bucket {
+    /// The number of tokens in the bucket at `now`
+    fn refilled(&self, now: Instant) -> f64 {
+        let added = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        (self.tokens + added).min(self.capacity)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LimitedUser {
+    type Error = RateLimitError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(request
+            .guard::<AuthenticatedUser>()
+            .await
+            .map_failure(|(status, err)| (status, RateLimitError::User(err))));
+        let (Some(limiter), Some(config)) = (
+            request.rocket().state::<RateLimiter>(),
+            request.rocket().state::<AppConfig>(),
+        ) else {
+            return Outcome::Success(LimitedUser(user));
+        };
+
+        let limits = &config.rate_limits;
+        let res = limiter.check(&[
+            (RateLimitKey::User(user.id), &limits.user),
+            (RateLimitKey::Instance(user.instance_id), &limits.instance),
+        ]);
+        match res {
+            Ok(()) => Outcome::Success(LimitedUser(user)),
+            Err(retry_after) => exceeded(request, retry_after),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LoginLimit {
+    type Error = RateLimitError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (Some(limiter), Some(config)) = (
+            request.rocket().state::<RateLimiter>(),
+            request.rocket().state::<AppConfig>(),
+        ) else {
+            return Outcome::Success(LoginLimit);
+        };
+        let Some(ip) = request.client_ip() else {
+            return Outcome::Failure((Status::BadRequest, RateLimitError::UnknownClient));
+        };
+
+        match limiter.check(&[(RateLimitKey::Login(ip), &config.rate_limits.login)]) {
+            Ok(()) => Outcome::Success(LoginLimit),
+            Err(retry_after) => exceeded(request, retry_after),
+        }
+    }
+}
+
+fn exceeded<T>(request: &Request<'_>, retry_after: Duration) -> Outcome<T, RateLimitError> {
+    request.local_cache(|| RetryAfter(Some(retry_after)));
+    Outcome::Failure((Status::TooManyRequests, RateLimitError::Exceeded))
+}
+
+impl Deref for LimitedUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Describe a wait of `seconds` for people, rounding up to minutes when over a minute
+pub(crate) fn describe_wait(seconds: u64) -> String {
+    match seconds {
+        1 => String::from("1 second"),
+        0..=60 => format!("{} seconds", seconds),
+        _ => format!("{} minutes", (seconds + 59) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(burst: u32) -> RateLimit {
+        RateLimit {
+            burst,
+            per_minute: 1,
+        }
+    }
+
+    #[test]
+    fn rejected_requests_take_no_tokens() {
+        let limiter = RateLimiter::default();
+        let user = RateLimitKey::User(UserId::from(1));
+        let other_user = RateLimitKey::User(UserId::from(2));
+        let instance = RateLimitKey::Instance(InstanceId::from(1));
+        let (user_limit, instance_limit) = (limit(2), limit(1));
+
+        assert!(limiter
+            .check(&[(user, &user_limit), (instance, &instance_limit)])
+            .is_ok());
+        // The instance is out of tokens, so the user keeps their remaining one
+        assert!(limiter
+            .check(&[(other_user, &user_limit), (instance, &instance_limit)])
+            .is_err());
+        assert!(limiter.check(&[(user, &user_limit)]).is_ok());
+        assert!(limiter.check(&[(user, &user_limit)]).is_err());
+    }
+
+    #[test]
+    fn disabled_limits() {
+        let limiter = RateLimiter::default();
+        let ip = RateLimitKey::Login(IpAddr::from([127, 0, 0, 1]));
+        let disabled = limit(0);
+        for _ in 0..10 {
+            assert!(limiter.check(&[(ip, &disabled)]).is_ok());
+        }
+    }
+}
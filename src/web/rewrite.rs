@@ -1,16 +1,27 @@
-use reqwest::header::AUTHORIZATION;
+use std::time::Instant;
+
+use reqwest::header::{HeaderMap, AUTHORIZATION, LINK};
 use rocket::http::uri::Origin;
-use rocket::response::{Flash, Redirect};
+use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::{http, Route};
+use rocket::{http, Route, State};
 use rocket_db_pools::Connection;
+use time::OffsetDateTime;
 use url::Url;
 
+use crate::backoff::Backoff;
 use crate::db::Db;
-use crate::web::session::AuthenticatedUser;
-use crate::{http_client, web};
-use crate::{json_or_error, ErrorResponse, FediurlError, RespondOrRedirect};
+use crate::http::HttpClient;
+use crate::metrics::{Metrics, RewriteOutcome};
+use crate::models::failure::{Failure, NewFailure};
+use crate::models::instance::{Instance, SearchApi};
+use crate::policy::{BlockedDomains, DomainList, InstancePolicy, UserBlocks};
+use crate::software::{self, Software};
+use crate::web::rate_limit::LimitedUser;
+use crate::web::session::{AuthenticatedUser, DOMAIN_BLOCKS_SCOPE};
+use crate::web::ServerTiming;
+use crate::{json_or_error, misskey, ErrorResponse, FediurlError};
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -26,6 +37,7 @@ struct Account {
     id: String,
     username: String,
     acct: String,
+    url: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -33,28 +45,56 @@ struct Account {
 struct Status {
     id: String,
     account: Account,
+    url: Option<String>,
 }
 
+const DOMAIN_BLOCKS_PAGE_SIZE: u32 = 200;
+/// Limit on the number of pages of domain blocks fetched for each lookup
+const DOMAIN_BLOCKS_MAX_PAGES: usize = 5;
+
 pub fn routes() -> Vec<Route> {
     routes![rewrite, rewrite_json]
 }
 
 /// URL rewrite endpoint, will redirect to equivalent URL on user's instance
 #[get("/https:/<_..>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
 async fn rewrite(
     mut db: Connection<Db>,
     // config: &State<AppConfig>,
-    user: AuthenticatedUser,
+    policy: &State<InstancePolicy>,
+    blocked: &State<BlockedDomains>,
+    user_blocks: &State<UserBlocks>,
+    backoff: &State<Backoff>,
+    client: &State<HttpClient>,
+    metrics: &State<Metrics>,
+    timing: &ServerTiming,
+    user: LimitedUser,
     origin: &Origin<'_>,
-) -> Result<RespondOrRedirect, FediurlError> {
-    match lookup(&mut db, &user, origin).await {
-        Ok(Some(url)) => Ok(RespondOrRedirect::Redirect(Redirect::to(url.to_string()))),
+) -> Result<Redirect, FediurlError> {
+    let res = lookup(
+        &mut db,
+        policy,
+        blocked,
+        user_blocks,
+        backoff,
+        client,
+        metrics,
+        timing,
+        &user,
+        origin,
+    )
+    .await;
+    record_outcome(metrics, &res);
+    match res {
+        Ok(Some(url)) => Ok(Redirect::to(url.to_string())),
         // not found
         Ok(None) => Err(FediurlError::InvalidPath), // TODO: Show no match page
-        Err(err) => Ok(RespondOrRedirect::FlashRedirect(Flash::error(
-            Redirect::to(uri!(web::home)),
-            format!("Error: {}", err),
-        ))),
+        // Shown on the error page for the status of the error
+        Err(err) => {
+            record_failure(&mut db, &user, &err).await;
+            Err(err)
+        }
     }
 }
 
@@ -73,103 +113,531 @@ enum RewriteResponse {
 
 // URL rewrite endpoint, JSON version
 #[get("/https:/<_..>", format = "json")]
+#[allow(clippy::too_many_arguments)]
 async fn rewrite_json(
     mut db: Connection<Db>,
     // config: &State<AppConfig>,
-    user: AuthenticatedUser,
+    policy: &State<InstancePolicy>,
+    blocked: &State<BlockedDomains>,
+    user_blocks: &State<UserBlocks>,
+    backoff: &State<Backoff>,
+    client: &State<HttpClient>,
+    metrics: &State<Metrics>,
+    timing: &ServerTiming,
+    user: LimitedUser,
     origin: &Origin<'_>,
-) -> Json<RewriteResponse> {
-    match lookup(&mut db, &user, origin).await {
-        Ok(Some(url)) => Json(RewriteResponse::Redirect(Rewrite {
-            destination: url.to_string(),
-        })),
-        // TODO: Set status to something other than 200 for these?
-        Ok(None) => Json(RewriteResponse::Error(ErrorResponse {
-            status: http::Status::NotFound.code,
-            error: "no_match".to_string(),
-            error_description: "No matching URL found".to_string(),
-        })),
+) -> (http::Status, Json<RewriteResponse>) {
+    let res = lookup(
+        &mut db,
+        policy,
+        blocked,
+        user_blocks,
+        backoff,
+        client,
+        metrics,
+        timing,
+        &user,
+        origin,
+    )
+    .await;
+    record_outcome(metrics, &res);
+    match res {
+        Ok(Some(url)) => (
+            http::Status::Ok,
+            Json(RewriteResponse::Redirect(Rewrite {
+                destination: url.to_string(),
+            })),
+        ),
+        Ok(None) => (
+            http::Status::NotFound,
+            Json(RewriteResponse::Error(ErrorResponse {
+                status: http::Status::NotFound.code,
+                error: "no_match".to_string(),
+                error_description: "No matching URL found".to_string(),
+                context: None,
+            })),
+        ),
         Err(err) => {
-            let resp = match err {
-                FediurlError::Database(err) => ErrorResponse {
-                    status: http::Status::InternalServerError.code,
-                    error: "database".to_string(),
-                    error_description: err.to_string(),
-                },
-                FediurlError::Http(err) => ErrorResponse {
-                    status: http::Status::InternalServerError.code,
-                    error: "http_client".to_string(),
-                    error_description: err.to_string(),
-                },
-                FediurlError::Io(err) => ErrorResponse {
-                    status: http::Status::InternalServerError.code,
-                    error: "io".to_string(),
-                    error_description: err.to_string(),
-                },
-                FediurlError::Url(err) => ErrorResponse {
-                    status: http::Status::BadRequest.code,
-                    error: "invalid_url".to_string(),
-                    error_description: err.to_string(),
-                },
-                FediurlError::InvalidPath => ErrorResponse {
-                    status: http::Status::NotFound.code,
-                    error: "invalid_path".to_string(),
-                    error_description: "path or URL was invalid or not found".to_string(),
-                },
-                FediurlError::ErrorResponse(err) => err,
-            };
-            Json(RewriteResponse::Error(resp))
+            record_failure(&mut db, &user, &err).await;
+            err.report(origin);
+            (
+                err.status(),
+                Json(RewriteResponse::Error(err.to_error_response())),
+            )
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn lookup(
     db: &mut Connection<Db>,
+    policy: &InstancePolicy,
+    blocked: &BlockedDomains,
+    user_blocks: &UserBlocks,
+    backoff: &Backoff,
+    client: &HttpClient,
+    metrics: &Metrics,
+    timing: &ServerTiming,
     user: &AuthenticatedUser,
     origin: &Origin<'_>,
 ) -> Result<Option<Url>, FediurlError> {
-    let instance = user.instance(&mut *db).await?; // Instance::from_id(&mut *db, user.instance_id).await?;
-    let client = http_client()?;
+    let instance = timing
+        .time("db", "Database", user.instance(&mut *db))
+        .await?;
+    // The policy may have changed since the user logged in
+    if !policy.permits(&instance.domain) {
+        return Err(FediurlError::InstanceNotPermitted(instance.domain));
+    }
+    timing
+        .time("db", "Database", record_use(db, user, &instance))
+        .await;
 
     // Build the remote_url
     let remote_url = &origin.to_string()[1..]; // skip leading slash
 
-    // Perform search to try to find URL on user's instance
-    let mut url = instance.url().join("/api/v2/search")?;
+    // Check the remote domain is not blocked before asking the instance to resolve (and
+    // potentially fetch) the URL
+    let remote_domain = Url::parse(remote_url)?
+        .host_str()
+        .map(str::to_string)
+        .ok_or(FediurlError::InvalidPath)?;
+    if blocked.0.matches(&remote_domain) {
+        return Err(FediurlError::BlockedDomain(remote_domain));
+    }
+    if user.has_scope(DOMAIN_BLOCKS_SCOPE) {
+        let blocks = match user_blocks.get(user.id) {
+            Some(blocks) => blocks,
+            None => {
+                let blocks = timing
+                    .time(
+                        "blocks",
+                        "Domain blocks",
+                        domain_blocks(client, backoff, &instance, user),
+                    )
+                    .await?;
+                user_blocks.insert(user.id, blocks)
+            }
+        };
+        if blocks.matches(&remote_domain) {
+            return Err(FediurlError::BlockedDomain(remote_domain));
+        }
+    }
+
+    let software = match instance.software() {
+        Some(software) if !instance.detection_due(OffsetDateTime::now_utc()) => {
+            metrics.software_cache(true);
+            software
+        }
+        _ => {
+            metrics.software_cache(false);
+            timing
+                .time(
+                    "cache",
+                    "Software detection",
+                    detect_software(db, client, &instance),
+                )
+                .await
+        }
+    };
+
+    // Misskey can resolve the URL directly
+    let start = Instant::now();
+    if software.is_misskey() {
+        let object =
+            misskey::ap_show(client, &instance.url(), &user.access_token, remote_url).await;
+        metrics.observe_upstream(software, start.elapsed());
+        timing.record("upstream", "Instance lookup", start.elapsed());
+        return Ok(Some(object?.url(&instance.url())));
+    }
+
+    // Perform search to try to find URL on user's instance, falling back to the other search API
+    // if the one expected to work isn't supported.
+    let search_api = instance.search_api().unwrap_or(SearchApi::V2);
+    let results = match search(client, backoff, &instance, user, search_api, remote_url).await {
+        Err(err)
+            if err
+                .response()
+                .map_or(false, |resp| is_unsupported(resp.status)) =>
+        {
+            let fallback = search_api.fallback();
+            info!(
+                "{} search unsupported on {}, trying {}",
+                search_api.as_str(),
+                instance.domain,
+                fallback.as_str()
+            );
+            let results = search(client, backoff, &instance, user, fallback, remote_url).await;
+            if results.is_ok() {
+                let update = Instance::update_search_api(&mut *db, instance.id, fallback);
+                timing.time("db", "Database", update).await?;
+            }
+            results
+        }
+        res => res,
+    };
+    metrics.observe_upstream(software, start.elapsed());
+    timing.record("upstream", "Instance search", start.elapsed());
+    let results = results?;
+
+    let start = Instant::now();
+    let url = result_url(&instance, software, &results);
+    timing.record("build", "URL build", start.elapsed());
+    Ok(url)
+}
+
+/// Pick a result and build its URL on the instance
+fn result_url(instance: &Instance, software: Software, results: &Search) -> Option<Url> {
+    // Pick a result, favouring statuses first
+    // TODO: Perhaps there needs to be a hint as whether we're expecting an account or status
+    if let Some(status) = results.statuses.first() {
+        return local_url(instance, status.url.as_deref())
+            .or_else(|| software.status_url(&instance.url(), &status.account.acct, &status.id));
+    }
+
+    // Try accounts
+    results.accounts.first().and_then(|account| {
+        local_url(instance, account.url.as_deref())
+            .or_else(|| software.account_url(&instance.url(), &account.acct, &account.id))
+    })
+}
+
+/// Count the outcome of a lookup in the metrics
+fn record_outcome(metrics: &Metrics, res: &Result<Option<Url>, FediurlError>) {
+    let outcome = match res {
+        Ok(Some(_)) => RewriteOutcome::Hit,
+        Ok(None) => RewriteOutcome::NoMatch,
+        Err(err) => RewriteOutcome::Error(err),
+    };
+    metrics.rewrite(outcome);
+}
+
+/// Record a failed lookup so that it shows up in the admin interface
+async fn record_failure(db: &mut Connection<Db>, user: &AuthenticatedUser, err: &FediurlError) {
+    // These aren't failures of the instance, or in the case of an unavailable instance the
+    // failure has already been recorded as a change in its health
+    if matches!(
+        err.inner(),
+        FediurlError::InvalidPath
+            | FediurlError::Banned(_)
+            | FediurlError::InstanceNotPermitted(_)
+            | FediurlError::BlockedDomain(_)
+            | FediurlError::InstanceUnavailable { .. }
+    ) {
+        return;
+    }
+
+    let domain = match user.instance(&mut *db).await {
+        Ok(instance) => instance.domain,
+        Err(_) => return,
+    };
+    let message = err.to_string();
+    let failure = NewFailure {
+        domain: &domain,
+        user_id: Some(user.id),
+        kind: err.kind(),
+        message: &message,
+    };
+    if let Err(err) = Failure::create(&mut *db, failure).await {
+        warn!("unable to record failure: {}", err);
+    }
+}
+
+/// Search for `q` on `instance` using the given search API
+async fn search(
+    client: &HttpClient,
+    backoff: &Backoff,
+    instance: &Instance,
+    user: &AuthenticatedUser,
+    search_api: SearchApi,
+    q: &str,
+) -> Result<Search, FediurlError> {
+    let mut url = instance.url().join(search_api.path())?;
     let bearer_token = format!("Bearer {}", user.access_token);
     url.query_pairs_mut()
-        .append_pair("q", &remote_url)
+        .append_pair("q", q)
         .append_pair("resolve", "true");
 
     // Fetch search results
-    let resp = client
-        .get(url.clone())
-        .header(AUTHORIZATION, &bearer_token)
-        .send()
-        .await?;
-    let results = json_or_error::<Search>(resp).await?;
+    let request = client.get(url).header(AUTHORIZATION, &bearer_token);
+    let resp = backoff.send(client, &user.access_token, request).await?;
+    json_or_error::<Search>(resp).await
+}
 
-    // Pick a result, favouring statuses first
-    // TODO: Perhaps there needs to be a hint as whether we're expecting an account or status
-    results
-        .statuses
-        .first()
-        .map(|status| {
-            let acct = format!("@{}", status.account.acct);
-            let mut url = instance.url();
-            // NOTE(unwrap): won't panic as instance URL is known to be valid as a base URL
-            url.path_segments_mut()
-                .unwrap()
-                .extend(&[&acct, &status.id]);
-            Ok(url)
-        })
-        .or_else(|| {
-            // Try accounts
-            results
-                .accounts
-                .first()
-                .map(|account| instance.url().join(&format!("@{}", account.acct)))
+/// Fetch the domains the user has blocked
+///
+/// Blocks apply to subdomains too, so the returned list matches them as well.
+async fn domain_blocks(
+    client: &HttpClient,
+    backoff: &Backoff,
+    instance: &Instance,
+    user: &AuthenticatedUser,
+) -> Result<DomainList, FediurlError> {
+    let mut url = instance.url().join("/api/v1/domain_blocks")?;
+    url.query_pairs_mut()
+        .append_pair("limit", &DOMAIN_BLOCKS_PAGE_SIZE.to_string());
+    let bearer_token = format!("Bearer {}", user.access_token);
+
+    let mut blocks = DomainList::default();
+    for _ in 0..DOMAIN_BLOCKS_MAX_PAGES {
+        let request = client.get(url).header(AUTHORIZATION, &bearer_token);
+        let resp = backoff.send(client, &user.access_token, request).await?;
+        let next = next_page(instance, resp.headers());
+        let page = json_or_error::<Vec<String>>(resp).await?;
+        blocks.extend(DomainList::new(
+            page.iter().map(|domain| format!("*.{}", domain)),
+        ));
+
+        match next {
+            Some(next) => url = next,
+            None => break,
+        }
+    }
+    Ok(blocks)
+}
+
+/// The URL of the next page of results from a `Link` header
+///
+/// The user's access token is sent with the request for the next page, so it is only followed if
+/// it is on `instance` itself.
+fn next_page(instance: &Instance, headers: &HeaderMap) -> Option<Url> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+    let next = link.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        if params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+        {
+            let url = url.trim().trim_start_matches('<').trim_end_matches('>');
+            Url::parse(url).ok()
+        } else {
+            None
+        }
+    })?;
+
+    if next.origin() == instance.url().origin() {
+        Some(next)
+    } else {
+        warn!(
+            "not following next page link from {} to another site: {}",
+            instance.domain, next
+        );
+        None
+    }
+}
+
+/// Whether an error status indicates that an API endpoint is not supported by the instance
+fn is_unsupported(status: u16) -> bool {
+    matches!(status, 404 | 405 | 410 | 501)
+}
+
+/// Returns `url` if it is a valid URL on `instance`
+fn local_url(instance: &Instance, url: Option<&str>) -> Option<Url> {
+    let url = Url::parse(url?).ok()?;
+    (url.host_str() == Some(instance.domain.as_str())).then_some(url)
+}
+
+/// Record when the user and their instance were last used
+///
+/// Failure to record this is not fatal, so that the rewrite can still go ahead.
+async fn record_use(db: &mut Connection<Db>, user: &AuthenticatedUser, instance: &Instance) {
+    let now = OffsetDateTime::now_utc();
+    if let Err(err) = user.record_use(&mut *db, now).await {
+        warn!("unable to record use of user {}: {}", user.id, err);
+    }
+    if let Err(err) = instance.record_use(&mut *db, now).await {
+        warn!("unable to record use of {}: {}", instance.domain, err);
+    }
+}
+
+/// Detect and record the software of an instance that hasn't been seen before
+///
+/// Failure to detect the software is not fatal, URLs are built as for Mastodon in that case. The
+/// failure is recorded as unknown software, so that detection isn't tried again for every rewrite.
+async fn detect_software(
+    db: &mut Connection<Db>,
+    client: &HttpClient,
+    instance: &Instance,
+) -> Software {
+    let name = match software::detect(client, &instance.url()).await {
+        Ok(name) => name,
+        Err(err) => {
+            warn!("unable to detect software of {}: {}", instance.domain, err);
+            Software::Unknown.as_str().to_string()
+        }
+    };
+    if let Err(err) = Instance::update_software(&mut *db, instance.id, &name).await {
+        warn!("unable to save software of {}: {}", instance.domain, err);
+    }
+    Software::from_name(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::{json, Value};
+
+    use super::*;
+    use crate::models::instance::InstanceId;
+
+    fn instance() -> Instance {
+        let now = OffsetDateTime::now_utc();
+        Instance {
+            id: InstanceId::from(1),
+            domain: String::from("example.social"),
+            client_id: String::new(),
+            client_secret: String::new(),
+            software: None,
+            software_detected_at: None,
+            search_api: None,
+            scopes: String::new(),
+            banned_until: None,
+            banned_at: None,
+            ban_reason: None,
+            created_at: now,
+            updated_at: now,
+            last_used_at: None,
+        }
+    }
+
+    fn account(url: &str) -> Value {
+        json!({
+            "id": "abcd",
+            "username": "alice",
+            "acct": "alice@remote.example",
+            "url": url,
         })
-        .transpose()
-        .map_err(FediurlError::from)
+    }
+
+    fn search(accounts: Vec<Value>, statuses: Vec<Value>) -> Search {
+        serde_json::from_value(json!({ "accounts": accounts, "statuses": statuses })).unwrap()
+    }
+
+    fn result(software: Software, results: &Search) -> Option<String> {
+        result_url(&instance(), software, results).map(String::from)
+    }
+
+    #[test]
+    fn local_url_on_instance() {
+        let url = "https://example.social/@alice@remote.example/1234";
+        assert_eq!(
+            local_url(&instance(), Some(url))
+                .map(String::from)
+                .as_deref(),
+            Some(url)
+        );
+    }
+
+    #[test]
+    fn local_url_elsewhere() {
+        let instance = instance();
+        assert_eq!(
+            local_url(&instance, Some("https://remote.example/@alice/1234")),
+            None
+        );
+        assert_eq!(local_url(&instance, Some("not a url")), None);
+        assert_eq!(local_url(&instance, None), None);
+    }
+
+    #[test]
+    fn result_prefers_local_status_url() {
+        let status = json!({
+            "id": "1234",
+            "account": account("https://remote.example/@alice"),
+            "url": "https://example.social/notice/1234",
+        });
+        let results = search(Vec::new(), vec![status]);
+        // The local URL is used even though the software would build a different one
+        assert_eq!(
+            result(Software::Mastodon, &results).as_deref(),
+            Some("https://example.social/notice/1234")
+        );
+    }
+
+    #[test]
+    fn result_builds_remote_status_url() {
+        let status = json!({
+            "id": "1234",
+            "account": account("https://remote.example/@alice"),
+            "url": "https://remote.example/@alice/5678",
+        });
+        let results = search(Vec::new(), vec![status]);
+        assert_eq!(
+            result(Software::Mastodon, &results).as_deref(),
+            Some("https://example.social/@alice@remote.example/1234")
+        );
+        assert_eq!(result(Software::GoToSocial, &results), None);
+    }
+
+    #[test]
+    fn result_favours_statuses_over_accounts() {
+        let status = json!({
+            "id": "1234",
+            "account": account("https://remote.example/@alice"),
+            "url": null,
+        });
+        let results = search(vec![account("https://remote.example/@alice")], vec![status]);
+        assert_eq!(
+            result(Software::Pleroma, &results).as_deref(),
+            Some("https://example.social/notice/1234")
+        );
+    }
+
+    #[test]
+    fn result_accounts() {
+        let local = search(
+            vec![account("https://example.social/users/alice")],
+            Vec::new(),
+        );
+        assert_eq!(
+            result(Software::Mastodon, &local).as_deref(),
+            Some("https://example.social/users/alice")
+        );
+
+        let remote = search(vec![account("https://remote.example/@alice")], Vec::new());
+        assert_eq!(
+            result(Software::Pleroma, &remote).as_deref(),
+            Some("https://example.social/users/abcd")
+        );
+        assert_eq!(result(Software::GoToSocial, &remote), None);
+    }
+
+    #[test]
+    fn result_empty() {
+        assert_eq!(
+            result(Software::Mastodon, &search(Vec::new(), Vec::new())),
+            None
+        );
+    }
+
+    fn link(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(LINK, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn next_page_on_instance() {
+        let headers = link(
+            r#"<https://example.social/api/v1/domain_blocks?max_id=2>; rel="next", <https://example.social/api/v1/domain_blocks?min_id=3>; rel="prev""#,
+        );
+        assert_eq!(
+            next_page(&instance(), &headers)
+                .map(String::from)
+                .as_deref(),
+            Some("https://example.social/api/v1/domain_blocks?max_id=2")
+        );
+        assert_eq!(next_page(&instance(), &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn next_page_elsewhere_is_ignored() {
+        for url in [
+            "https://attacker.example/api/v1/domain_blocks",
+            "http://example.social/api/v1/domain_blocks",
+            "https://example.social:8443/api/v1/domain_blocks",
+            "https://example.social.attacker.example/api/v1/domain_blocks",
+        ] {
+            let headers = link(&format!(r#"<{}>; rel="next""#, url));
+            assert_eq!(next_page(&instance(), &headers), None, "{}", url);
+        }
+    }
 }
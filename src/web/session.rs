@@ -1,5 +1,8 @@
 //! User authentication/session management.
 
+pub(super) mod registration;
+
+use reqwest::header::AUTHORIZATION;
 use reqwest::Url;
 use std::ops::Deref;
 
@@ -7,28 +10,49 @@ use std::ops::Deref;
 
 use rocket::form::{Context, Contextual, Form};
 use rocket::http::uri::{Absolute, Host};
-use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::outcome::{try_outcome, IntoOutcome};
 use rocket::request::{FlashMessage, FromRequest, Outcome, Request};
 use rocket::response::content::RawHtml;
 use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Value;
 use rocket::serde::Deserialize;
 use rocket::{Route, State};
 use rocket_db_pools::Connection;
 use time::Duration; // for Cookie
+use time::OffsetDateTime;
 
 use crate::config::AppConfig;
 use crate::db::Db;
 use crate::form::{validate, ContextExt, NonEmptyString};
+use crate::http::HttpClient;
+use crate::metrics::{LoginStage, Metrics};
+use crate::models::failure::{Failure, NewFailure};
 use crate::models::instance::{Instance, NewInstance};
 use crate::models::user::{NewUser, User};
+use crate::policy::InstancePolicy;
+use crate::software::{self, Software};
 use crate::templates::{self, Layout, Title};
-use crate::web::XForwardedProto;
-use crate::{html, http_client, json_or_error, web, FediurlError, RespondOrRedirect};
+use crate::web::rate_limit::LoginLimit;
+use crate::web::{BanNotice, XForwardedProto};
+use crate::{html, json_or_error, misskey, web, FediurlError, RespondOrRedirect};
+use registration::{
+    authorize_url, client_credentials_valid, register_app, reregister_app, CheckedCredentials,
+    RegistrationError,
+};
 
 pub const FEDIURL_SESSION: &str = "FEDIURL_SESSION";
-const SCOPES: &str = "read:search";
+const FEDIURL_MIAUTH: &str = "FEDIURL_MIAUTH";
+/// Cookie holding the scopes requested when the user was sent to authorise Fediurl
+const FEDIURL_SCOPES: &str = "FEDIURL_SCOPES";
+/// Scopes the application is registered with
+const SCOPES: &str = "read:search read:accounts read:blocks";
+/// Scopes requested when logging in, users may also opt in to `DOMAIN_BLOCKS_SCOPE`
+const LOGIN_SCOPES: &str = "read:search read:accounts";
+pub(crate) const DOMAIN_BLOCKS_SCOPE: &str = "read:blocks";
 const FEDIURL_WEBSITE: &str = "https://fediurl.7bit.org/";
+/// OAuth error returned when the client id or secret is not recognised
+const INVALID_CLIENT: &str = "invalid_client";
 
 pub struct AuthenticatedUser(User);
 
@@ -36,26 +60,20 @@ pub struct AuthenticatedUser(User);
 struct LoginForm<'v> {
     #[field(validate=validate::domain().map(drop))]
     instance: NonEmptyString<'v>,
-}
-
-#[derive(Deserialize)]
-#[serde(crate = "rocket::serde")]
-struct Application {
-    name: String,
-    // website: Option<String>,
-    // vapid_key: String,
-    client_id: Option<String>,
-    client_secret: Option<String>,
+    /// Don't resolve content from domains the user has blocked
+    domain_blocks: bool,
 }
 
 #[derive(Debug)]
 pub enum AuthenticatedUserError {
     Database(sqlx::Error),
     GuardFailure,
+    /// The user or their instance is banned
+    Banned,
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![new, new_redirect, create, delete, auth]
+    routes![new, new_redirect, create, delete, auth, miauth]
 }
 
 #[rocket::async_trait]
@@ -76,11 +94,26 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
             .and_then(|cookie| cookie.value().parse().ok())
             .or_forward(()));
 
-        User::from_id(&mut *db, user_id)
+        let user = try_outcome!(User::from_id(&mut *db, user_id)
             .await
-            .map(AuthenticatedUser)
-            .map_err(|err| err.into())
-            .or_forward(())
+            .map_err(AuthenticatedUserError::from)
+            .or_forward(()));
+
+        // Refuse users that are banned, or whose instance is banned
+        let ban = match user.account_ban(&mut *db).await {
+            Ok(Some(ban)) => Some(ban),
+            Ok(None) => match user.instance(&mut *db).await {
+                Ok(instance) => instance.active_ban(),
+                Err(err) => return Outcome::Failure((Status::InternalServerError, err.into())),
+            },
+            Err(err) => return Outcome::Failure((Status::InternalServerError, err.into())),
+        };
+        if let Some(ban) = ban {
+            request.local_cache(|| BanNotice(Some(ban)));
+            return Outcome::Failure((Status::Forbidden, AuthenticatedUserError::Banned));
+        }
+
+        Outcome::Success(AuthenticatedUser(user))
     }
 }
 
@@ -119,84 +152,149 @@ pub async fn new(
 }
 
 #[post("/login", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
 async fn create(
+    _limit: LoginLimit,
     host: &Host<'_>,
     proto: Option<XForwardedProto<'_>>,
     mut db: Connection<Db>,
     config: &State<AppConfig>,
+    policy: &State<InstancePolicy>,
+    client: &State<HttpClient>,
+    checked: &State<CheckedCredentials>,
+    metrics: &State<Metrics>,
     cookies: &CookieJar<'_>,
     form: Form<Contextual<'_, LoginForm<'_>>>,
 ) -> Result<RespondOrRedirect, FediurlError> {
     match form.value {
         // Form was valid, try logging the user in
         Some(ref submission) => {
+            metrics.login(LoginStage::Started);
+            if !policy.permits(&submission.instance) {
+                let err = FediurlError::InstanceNotPermitted(submission.instance.to_string());
+                let flash = Flash::error(cookies, err.to_string());
+                return render_new(config, flash, &form.context);
+            }
+
             let instance = Instance::from_domain_optional(&mut *db, &submission.instance).await;
 
             match instance {
                 Ok(Some(instance)) => {
+                    if let Some(ban) = instance.active_ban() {
+                        let flash = Flash::error(cookies, ban.to_string());
+                        return render_new(config, flash, &form.context);
+                    }
+
+                    // The software may not be known if detection previously failed or the
+                    // cache was cleared
+                    let software = match instance.software() {
+                        Some(software) if !instance.detection_due(OffsetDateTime::now_utc()) => {
+                            Some(software)
+                        }
+                        _ => detect_software(&mut db, client, &instance).await,
+                    };
+                    if software.map_or(false, |software| software.is_misskey()) {
+                        return miauth_redirect(host, &proto, config, cookies, &instance.domain);
+                    }
+
                     // Instance already exists so we can redirect to the auth page directly
                     // Determine the host we're running on
                     let prefix = safe_host(host, &proto, &config);
                     let redirect_uri =
                         uri!(prefix, auth(domain = &instance.domain, code = _)).to_string();
-                    let mut auth_url = instance.url().join("/oauth/authorize")?;
-                    auth_url
-                        .query_pairs_mut()
-                        .append_pair("response_type", "code")
-                        .append_pair("client_id", &instance.client_id)
-                        .append_pair("redirect_uri", &redirect_uri)
-                        .append_pair("scope", SCOPES);
-                    Ok(RespondOrRedirect::Redirect(Redirect::to(
-                        auth_url.to_string(),
-                    )))
+
+                    // Make sure the instance still knows about our application, it may have been
+                    // removed by the instance admin. A new application is also needed if it was
+                    // registered with different scopes.
+                    let valid = instance.scopes == SCOPES
+                        && client_credentials_valid(client, checked, &instance).await;
+                    let instance = if valid {
+                        Ok(instance)
+                    } else {
+                        reregister_app(&mut db, client, checked, instance, &redirect_uri).await
+                    };
+                    let instance = match instance {
+                        Ok(instance) => instance,
+                        Err(err) => {
+                            let domain = &submission.instance;
+                            return registration_failed(
+                                &mut db,
+                                config,
+                                cookies,
+                                &form.context,
+                                domain,
+                                err,
+                            )
+                            .await;
+                        }
+                    };
+
+                    let scopes = login_scopes(submission.domain_blocks);
+                    authorize_redirect(&proto, cookies, &instance, &redirect_uri, &scopes)
                 }
                 Ok(None) => {
                     // This is a newly encountered instance
-                    let client = http_client()?;
                     let domain = &*submission.instance;
                     let instance_url = Url::parse(&format!("https://{}/", domain))?;
 
+                    let software = match software::detect(client, &instance_url).await {
+                        Ok(name) => Some(name),
+                        Err(err) => {
+                            warn!("unable to detect software of {}: {}", domain, err);
+                            None
+                        }
+                    };
+
+                    // Misskey has no app registration, the application details are supplied
+                    // with each MiAuth request instead.
+                    if software
+                        .as_deref()
+                        .map_or(false, |name| Software::from_name(name).is_misskey())
+                    {
+                        let new_instance = NewInstance {
+                            domain: domain.to_string(),
+                            client_id: String::new(),
+                            client_secret: String::new(),
+                            software,
+                            scopes: String::new(),
+                        };
+                        Instance::create(&mut *db, new_instance).await?;
+                        return miauth_redirect(host, &proto, config, cookies, domain);
+                    }
+
                     // Register application to obtain client id and secret
                     let prefix = safe_host(host, &proto, &config);
                     let redirect_uri = uri!(prefix, auth(domain = domain, code = _)).to_string();
-                    let url = instance_url.join("/api/v1/apps")?;
-                    let resp = client
-                        .post(url)
-                        .form(&[
-                            ("client_name", crate::NAME),
-                            ("redirect_uris", &redirect_uri),
-                            ("scopes", SCOPES),
-                            ("website", FEDIURL_WEBSITE),
-                        ])
-                        .send()
-                        .await?; // TODO: Add context info to error
-                    let app = json_or_error::<Application>(resp).await?;
-
-                    let (Some(client_id), Some(client_secret)) = (app.client_id, app.client_secret) else {
-                        todo!("Render form again with flash message")
-                    };
-                    debug!("Got application: {}, ID: {}", app.name, client_id);
+                    let (client_id, client_secret) =
+                        match register_app(client, &instance_url, &redirect_uri).await {
+                            Ok(credentials) => credentials,
+                            Err(err) => {
+                                return registration_failed(
+                                    &mut db,
+                                    config,
+                                    cookies,
+                                    &form.context,
+                                    domain,
+                                    err,
+                                )
+                                .await
+                            }
+                        };
 
                     let new_instance = NewInstance {
                         domain: submission.instance.to_string(),
                         client_id,
                         client_secret,
+                        software,
+                        scopes: SCOPES.to_string(),
                     };
 
                     let instance_id = Instance::create(&mut *db, new_instance).await?;
                     let instance = Instance::from_id(&mut *db, instance_id).await?;
+                    checked.insert(&instance);
 
-                    // TODO: Extract method
-                    let mut auth_url = instance.url().join("/oauth/authorize")?;
-                    auth_url
-                        .query_pairs_mut()
-                        .append_pair("response_type", "code")
-                        .append_pair("client_id", &instance.client_id)
-                        .append_pair("redirect_uri", &redirect_uri)
-                        .append_pair("scope", SCOPES);
-                    Ok(RespondOrRedirect::Redirect(Redirect::to(
-                        auth_url.to_string(),
-                    )))
+                    let scopes = login_scopes(submission.domain_blocks);
+                    authorize_redirect(&proto, cookies, &instance, &redirect_uri, &scopes)
                 }
                 Err(err) => Err(FediurlError::from(err).into()),
             }
@@ -225,6 +323,134 @@ fn safe_host<'r>(
         .expect("FIXME: flash redirect with error message")
 }
 
+/// The URL an instance redirects the user back to once they have authorised Fediurl
+pub(super) fn auth_redirect_uri(
+    host: &Host<'_>,
+    proto: &Option<XForwardedProto<'_>>,
+    config: &AppConfig,
+    domain: &str,
+) -> String {
+    let prefix = safe_host(host, proto, config);
+    uri!(prefix, auth(domain = domain, code = _)).to_string()
+}
+
+/// Redirect the user to their instance to authorise Fediurl with `scopes`
+///
+/// The scopes are remembered so that authorisation can be restarted with the same ones if the
+/// application has to be registered again.
+fn authorize_redirect(
+    proto: &Option<XForwardedProto<'_>>,
+    cookies: &CookieJar<'_>,
+    instance: &Instance,
+    redirect_uri: &str,
+    scopes: &str,
+) -> Result<RespondOrRedirect, FediurlError> {
+    let url = authorize_url(instance, redirect_uri, scopes)?;
+
+    let cookie = Cookie::build(FEDIURL_SCOPES, scopes.to_string())
+        .path("/")
+        .secure(proto.map_or(false, |proto| &*proto == "https"))
+        .http_only(true)
+        .max_age(Duration::minutes(30))
+        .same_site(SameSite::Lax)
+        .finish();
+    cookies.add_private(cookie);
+
+    Ok(RespondOrRedirect::Redirect(Redirect::to(url.to_string())))
+}
+
+/// Start a MiAuth session and redirect the user to their instance to authorise it
+fn miauth_redirect(
+    host: &Host<'_>,
+    proto: &Option<XForwardedProto<'_>>,
+    config: &AppConfig,
+    cookies: &CookieJar<'_>,
+    domain: &str,
+) -> Result<RespondOrRedirect, FediurlError> {
+    let session = format!("{:032x}", rand::random::<u128>());
+    let prefix = safe_host(host, proto, config);
+    let callback = uri!(prefix, miauth(domain = domain, session = _)).to_string();
+    let instance_url = Url::parse(&format!("https://{}/", domain))?;
+    let url = misskey::miauth_url(&instance_url, &session, &callback)?;
+
+    // Remember the session so that the callback can be verified
+    let cookie = Cookie::build(FEDIURL_MIAUTH, session)
+        .path("/")
+        .secure(proto.map_or(false, |proto| &*proto == "https"))
+        .http_only(true)
+        .max_age(Duration::minutes(30))
+        .same_site(SameSite::Lax)
+        .finish();
+    cookies.add_private(cookie);
+
+    Ok(RespondOrRedirect::Redirect(Redirect::to(url.to_string())))
+}
+
+/// Detect and record the software of an existing instance
+async fn detect_software(
+    db: &mut Connection<Db>,
+    client: &HttpClient,
+    instance: &Instance,
+) -> Option<Software> {
+    match software::detect(client, &instance.url()).await {
+        Ok(name) => {
+            if let Err(err) = Instance::update_software(&mut *db, instance.id, &name).await {
+                warn!("unable to save software of {}: {}", instance.domain, err);
+            }
+            Some(Software::from_name(&name))
+        }
+        Err(err) => {
+            warn!("unable to detect software of {}: {}", instance.domain, err);
+            None
+        }
+    }
+}
+
+/// Re-render the login page with details of why registering with the instance failed
+async fn registration_failed<'v>(
+    db: &mut Connection<Db>,
+    config: &AppConfig,
+    cookies: &CookieJar<'_>,
+    context: &Context<'v>,
+    domain: &str,
+    err: RegistrationError,
+) -> Result<RespondOrRedirect, FediurlError> {
+    warn!("unable to register with {}: {:?}", domain, err);
+    record_registration_failure(db, domain, &err).await;
+    let flash = Flash::error(
+        cookies,
+        format!("Unable to log in with {}: {}", domain, err),
+    );
+    render_new(config, flash, context)
+}
+
+/// Record a failure to register so that it shows up in the admin interface
+async fn record_registration_failure(
+    db: &mut Connection<Db>,
+    domain: &str,
+    err: &RegistrationError,
+) {
+    let message = err.to_string();
+    let failure = NewFailure {
+        domain,
+        user_id: None,
+        kind: "registration",
+        message: &message,
+    };
+    if let Err(err) = Failure::create(&mut *db, failure).await {
+        warn!("unable to record failure: {}", err);
+    }
+}
+
+/// The scopes to request when logging in
+fn login_scopes(domain_blocks: bool) -> String {
+    if domain_blocks {
+        format!("{} {}", LOGIN_SCOPES, DOMAIN_BLOCKS_SCOPE)
+    } else {
+        LOGIN_SCOPES.to_string()
+    }
+}
+
 fn render_new<'v>(
     config: &AppConfig,
     flash: FlashMessage<'_>,
@@ -253,10 +479,58 @@ fn delete(cookies: &CookieJar<'_>) -> Flash<Redirect> {
 #[serde(crate = "rocket::serde")]
 struct TokenResponse {
     access_token: String,
+    /// The scopes that were granted
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CredentialAccount {
+    username: String,
+}
+
+/// Retrieve the account that an access token belongs to
+async fn verify_credentials(
+    client: &HttpClient,
+    instance: &Instance,
+    access_token: &str,
+) -> Result<CredentialAccount, FediurlError> {
+    let url = instance.url().join("/api/v1/accounts/verify_credentials")?;
+    let request = client
+        .get(url)
+        .header(AUTHORIZATION, format!("Bearer {}", access_token));
+    let resp = client.send(request).await?;
+    json_or_error(resp).await
+}
+
+/// Revoke an access token issued to Fediurl, returning whether it could be revoked
+///
+/// Only tokens issued through OAuth can be revoked. Those issued through MiAuth remain in the
+/// user's list of authorised applications on their instance until they remove them.
+pub async fn revoke_token(
+    client: &HttpClient,
+    instance: &Instance,
+    access_token: &str,
+) -> Result<bool, FediurlError> {
+    // MiAuth instances have no client credentials
+    if instance.client_id.is_empty() {
+        return Ok(false);
+    }
+
+    let url = instance.url().join("/oauth/revoke")?;
+    let request = client.post(url).form(&[
+        ("client_id", instance.client_id.as_str()),
+        ("client_secret", &instance.client_secret),
+        ("token", access_token),
+    ]);
+    let resp = client.send(request).await?;
+    json_or_error::<Value>(resp).await?;
+    Ok(true)
 }
 
 /// OAuth authentication callback endpoint
 #[get("/auth/<domain>?<code>")]
+#[allow(clippy::too_many_arguments)]
 async fn auth(
     // This is only optional to allow uri generation without the code query parameter. All actual
     // request require the parameter to be present.
@@ -266,39 +540,197 @@ async fn auth(
     code: Option<&str>,
     mut db: Connection<Db>,
     config: &State<AppConfig>,
+    policy: &State<InstancePolicy>,
+    client: &State<HttpClient>,
+    checked: &State<CheckedCredentials>,
+    metrics: &State<Metrics>,
     cookies: &CookieJar<'_>,
 ) -> Result<RespondOrRedirect, FediurlError> {
     let Some(code) = code else {
-        return Err(FediurlError::InvalidPath)
+        return Err(FediurlError::InvalidPath);
     };
+    metrics.login(LoginStage::Authorized);
+    if !policy.permits(domain) {
+        return Err(FediurlError::InstanceNotPermitted(domain.to_string()));
+    }
     let instance = Instance::from_domain(&mut *db, domain).await?;
-    let client = http_client()?;
+    if let Some(ban) = instance.active_ban() {
+        return Err(FediurlError::Banned(ban));
+    }
 
     // Use client id, secret, and code to get a token
     let prefix = safe_host(host, &proto, &config);
     let redirect_uri = uri!(prefix, auth(domain = domain, code = _)).to_string();
 
     let url = instance.url().join("/oauth/token")?;
-    let resp = client
-        .post(url)
-        .form(&[
-            ("grant_type", "authorization_code"),
-            ("code", code),
-            ("client_id", &instance.client_id),
-            ("client_secret", &instance.client_secret),
-            ("redirect_uri", &redirect_uri.to_string()),
-            ("scope", SCOPES),
-        ])
-        .send()
-        .await?; // TODO: Add context info to error
-    let token = json_or_error::<TokenResponse>(resp).await?;
-
-    // Create the user record.
-    let new_user = NewUser {
-        instance_id: instance.id,
-        access_token: token.access_token,
+    let request = client.post(url).form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("client_id", &instance.client_id),
+        ("client_secret", &instance.client_secret),
+        ("redirect_uri", &redirect_uri.to_string()),
+        ("scope", SCOPES),
+    ]);
+    let resp = client.send(request).await?;
+    let token = match json_or_error::<TokenResponse>(resp).await {
+        // The application has been removed from the instance since the user was sent to
+        // authorise it. Register it again and restart the authorisation.
+        Err(err)
+            if err
+                .response()
+                .map_or(false, |resp| resp.error == INVALID_CLIENT) =>
+        {
+            let instance =
+                match reregister_app(&mut db, client, checked, instance, &redirect_uri).await {
+                    Ok(instance) => instance,
+                    Err(err) => {
+                        warn!("unable to register with {}: {:?}", domain, err);
+                        record_registration_failure(&mut db, domain, &err).await;
+                        return Ok(RespondOrRedirect::FlashRedirect(Flash::error(
+                            Redirect::to(uri!(new)),
+                            format!("Unable to log in with {}: {}", domain, err),
+                        )));
+                    }
+                };
+            // Ask for the same scopes again, so that opting in to domain blocks isn't lost
+            let scopes = cookies
+                .get_private(FEDIURL_SCOPES)
+                .map(|cookie| cookie.value().to_string())
+                .unwrap_or_else(|| LOGIN_SCOPES.to_string());
+            return authorize_redirect(&proto, cookies, &instance, &redirect_uri, &scopes);
+        }
+        res => res?,
+    };
+    cookies.remove_private(Cookie::named(FEDIURL_SCOPES));
+
+    // The account is needed to check whether it has been banned, so the log in can't go ahead
+    // without it
+    let account = match verify_credentials(client, &instance, &token.access_token).await {
+        Ok(account) => format!("{}@{}", account.username, instance.domain),
+        Err(err) => {
+            warn!(
+                "unable to verify credentials on {}: {}",
+                instance.domain, err
+            );
+            discard_token(client, &instance, &token.access_token).await;
+            return Ok(account_unknown(&instance));
+        }
     };
-    let user_id = User::create(&mut *db, new_user).await?; // FIXME: Report nicer error
+
+    log_in(
+        &mut db,
+        client,
+        metrics,
+        cookies,
+        &proto,
+        &instance,
+        token.access_token,
+        account,
+        token.scope,
+    )
+    .await
+}
+
+/// MiAuth authentication callback endpoint
+#[get("/miauth/<domain>?<session>")]
+#[allow(clippy::too_many_arguments)]
+async fn miauth(
+    // As with `auth` the session is only optional to allow uri generation.
+    proto: Option<XForwardedProto<'_>>,
+    domain: &str,
+    session: Option<&str>,
+    mut db: Connection<Db>,
+    policy: &State<InstancePolicy>,
+    client: &State<HttpClient>,
+    metrics: &State<Metrics>,
+    cookies: &CookieJar<'_>,
+) -> Result<RespondOrRedirect, FediurlError> {
+    let Some(session) = session else {
+        return Err(FediurlError::InvalidPath);
+    };
+    metrics.login(LoginStage::Authorized);
+    if !policy.permits(domain) {
+        return Err(FediurlError::InstanceNotPermitted(domain.to_string()));
+    }
+
+    // Ensure the session is the one that was started for this browser
+    let expected = cookies.get_private(FEDIURL_MIAUTH);
+    if expected.as_ref().map(|cookie| cookie.value()) != Some(session) {
+        return Err(FediurlError::InvalidPath);
+    }
+    cookies.remove_private(Cookie::named(FEDIURL_MIAUTH));
+
+    let instance = Instance::from_domain(&mut *db, domain).await?;
+    if let Some(ban) = instance.active_ban() {
+        return Err(FediurlError::Banned(ban));
+    }
+    let check = misskey::check(client, &instance.url(), session).await?;
+    let (true, Some(token)) = (check.ok, check.token) else {
+        return Ok(RespondOrRedirect::FlashRedirect(Flash::error(
+            Redirect::to(uri!(new)),
+            format!(
+                "{} did not authorise the log in, please try again",
+                instance.domain
+            ),
+        )));
+    };
+
+    let Some(user) = check.user else {
+        warn!(
+            "MiAuth check on {} did not include the user",
+            instance.domain
+        );
+        discard_token(client, &instance, &token).await;
+        return Ok(account_unknown(&instance));
+    };
+    let account = format!("{}@{}", user.username, instance.domain);
+    // Domain blocks are only checked via the Mastodon API, so MiAuth permissions aren't recorded
+    log_in(
+        &mut db, client, metrics, cookies, &proto, &instance, token, account, None,
+    )
+    .await
+}
+
+/// Record the user and set the session cookie
+///
+/// Logging in again to an account that has been used before reuses its user, so that any ban on
+/// it still applies. The user's previous access token is replaced and revoked.
+#[allow(clippy::too_many_arguments)]
+async fn log_in(
+    db: &mut Connection<Db>,
+    client: &HttpClient,
+    metrics: &Metrics,
+    cookies: &CookieJar<'_>,
+    proto: &Option<XForwardedProto<'_>>,
+    instance: &Instance,
+    access_token: String,
+    account: String,
+    scopes: Option<String>,
+) -> Result<RespondOrRedirect, FediurlError> {
+    let existing = User::from_account(&mut *db, instance.id, &account).await?;
+    let user_id = match existing {
+        Some(user) => {
+            if let Some(ban) = user.account_ban(&mut *db).await? {
+                discard_token(client, instance, &access_token).await;
+                return Err(FediurlError::Banned(ban));
+            }
+            User::update_token(&mut *db, user.id, &access_token, scopes.as_deref()).await?;
+            if user.access_token != access_token {
+                discard_token(client, instance, &user.access_token).await;
+            }
+            user.id
+        }
+        None => {
+            let new_user = NewUser {
+                instance_id: instance.id,
+                access_token,
+                account: Some(account),
+                scopes,
+            };
+            User::create(&mut *db, new_user).await? // FIXME: Report nicer error
+        }
+    };
+    metrics.login(LoginStage::Completed);
 
     // Set login cookie
     let cookie = Cookie::build(FEDIURL_SESSION, user_id.value().to_string())
@@ -316,6 +748,24 @@ async fn auth(
     )))
 }
 
+/// Send the user back to the log in page when their account couldn't be looked up
+fn account_unknown(instance: &Instance) -> RespondOrRedirect {
+    RespondOrRedirect::FlashRedirect(Flash::error(
+        Redirect::to(uri!(new)),
+        format!(
+            "Unable to look up your account on {}, please try again",
+            instance.domain
+        ),
+    ))
+}
+
+/// Revoke an access token that is no longer needed, failure to do so is logged
+async fn discard_token(client: &HttpClient, instance: &Instance, access_token: &str) {
+    if let Err(err) = revoke_token(client, instance, access_token).await {
+        warn!("unable to revoke token on {}: {}", instance.domain, err);
+    }
+}
+
 impl AuthenticatedUser {
     pub fn id(&self) -> i64 {
         self.0.id.value()
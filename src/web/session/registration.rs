@@ -0,0 +1,279 @@
+//! Registration of Fediurl as an OAuth application on Mastodon compatible instances.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::{error, fmt, io};
+
+use reqwest::Url;
+use rocket::serde::Deserialize;
+use rocket_db_pools::Connection;
+
+use super::{revoke_token, TokenResponse, FEDIURL_WEBSITE, INVALID_CLIENT, SCOPES};
+use crate::db::Db;
+use crate::http::HttpClient;
+use crate::models::instance::{Instance, InstanceId};
+use crate::{json_or_error, ErrorResponse, FediurlError};
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Application {
+    name: String,
+    // website: Option<String>,
+    // vapid_key: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// The client id of each instance that was found to be accepted since Fediurl started
+///
+/// Credentials are checked once per registration rather than on every log in.
+#[derive(Default)]
+pub struct CheckedCredentials {
+    client_ids: Mutex<HashMap<InstanceId, String>>,
+}
+
+/// The ways in which registering with an instance can fail
+#[derive(Debug)]
+pub enum RegistrationError {
+    /// The instance responded successfully but without a client id and secret
+    MissingCredentials,
+    /// The instance response could not be parsed
+    InvalidResponse(reqwest::Error),
+    /// The instance did not respond in time
+    Timeout,
+    /// A TLS connection could not be established, e.g. due to an invalid certificate
+    Tls(reqwest::Error),
+    /// The instance could not be connected to
+    Connect(reqwest::Error),
+    /// The instance does not have the Mastodon app registration API
+    NotSupported,
+    /// The instance returned an error response
+    Rejected(ErrorResponse),
+    Other(FediurlError),
+}
+
+/// Register Fediurl as an application on an instance, returning the client id and secret
+pub(super) async fn register_app(
+    client: &HttpClient,
+    instance_url: &Url,
+    redirect_uri: &str,
+) -> Result<(String, String), RegistrationError> {
+    let url = instance_url
+        .join("/api/v1/apps")
+        .map_err(FediurlError::from)?;
+    let request = client.post(url).form(&[
+        ("client_name", crate::NAME),
+        ("redirect_uris", redirect_uri),
+        ("scopes", SCOPES),
+        ("website", FEDIURL_WEBSITE),
+    ]);
+    let resp = client.send(request).await?;
+    let app = json_or_error::<Application>(resp).await?;
+
+    let (Some(client_id), Some(client_secret)) = (app.client_id, app.client_secret) else {
+        return Err(RegistrationError::MissingCredentials);
+    };
+    debug!("Got application: {}, ID: {}", app.name, client_id);
+
+    Ok((client_id, client_secret))
+}
+
+/// Register the application again for an instance that no longer accepts its client credentials,
+/// or where it was registered with different scopes
+pub(crate) async fn reregister_app(
+    db: &mut Connection<Db>,
+    client: &HttpClient,
+    checked: &CheckedCredentials,
+    instance: Instance,
+    redirect_uri: &str,
+) -> Result<Instance, RegistrationError> {
+    warn!(
+        "client credentials for {} are no longer usable, registering again",
+        instance.domain
+    );
+    let (client_id, client_secret) = register_app(client, &instance.url(), redirect_uri).await?;
+    Instance::update_credentials(&mut *db, instance.id, &client_id, &client_secret, SCOPES)
+        .await
+        .map_err(FediurlError::from)?;
+    let instance = Instance {
+        client_id,
+        client_secret,
+        scopes: SCOPES.to_string(),
+        ..instance
+    };
+    checked.insert(&instance);
+    Ok(instance)
+}
+
+/// Check that an instance still accepts the client credentials that were issued to Fediurl
+///
+/// This is done by requesting an application token, which is revoked straight away. An instance
+/// that no longer knows the application says so on its authorisation page, which never returns
+/// the user to Fediurl, so this has to be checked before sending them there. Credentials that
+/// were already checked, or were registered, since Fediurl started are not checked again.
+///
+/// Only an `invalid_client` error means the credentials were rejected. Other errors, such as the
+/// instance not supporting the client credentials grant, are logged and the credentials are
+/// assumed to be valid so that logging in can carry on.
+pub(super) async fn client_credentials_valid(
+    client: &HttpClient,
+    checked: &CheckedCredentials,
+    instance: &Instance,
+) -> bool {
+    if checked.contains(instance) {
+        return true;
+    }
+
+    let token = match app_token(client, instance).await {
+        Ok(token) => token,
+        Err(err)
+            if err
+                .response()
+                .map_or(false, |resp| resp.error == INVALID_CLIENT) =>
+        {
+            return false
+        }
+        Err(err) => {
+            warn!(
+                "unable to check client credentials for {}: {}",
+                instance.domain, err
+            );
+            return true;
+        }
+    };
+
+    if let Err(err) = revoke_token(client, instance, &token.access_token).await {
+        warn!(
+            "unable to revoke application token on {}: {}",
+            instance.domain, err
+        );
+    }
+
+    checked.insert(instance);
+    true
+}
+
+impl CheckedCredentials {
+    /// Whether the current client credentials of `instance` were already checked
+    fn contains(&self, instance: &Instance) -> bool {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let client_ids = self.client_ids.lock().unwrap();
+        client_ids.get(&instance.id) == Some(&instance.client_id)
+    }
+
+    /// Record that the current client credentials of `instance` are accepted
+    pub(super) fn insert(&self, instance: &Instance) {
+        // NOTE(unwrap): the lock is not held across anything that can panic
+        let mut client_ids = self.client_ids.lock().unwrap();
+        client_ids.insert(instance.id, instance.client_id.clone());
+    }
+}
+
+/// Request a token for the application itself, rather than on behalf of a user
+async fn app_token(
+    client: &HttpClient,
+    instance: &Instance,
+) -> Result<TokenResponse, FediurlError> {
+    let url = instance.url().join("/oauth/token")?;
+    let request = client.post(url).form(&[
+        ("grant_type", "client_credentials"),
+        ("client_id", &instance.client_id),
+        ("client_secret", &instance.client_secret),
+        ("scope", SCOPES),
+    ]);
+    let resp = client.send(request).await?;
+    json_or_error::<TokenResponse>(resp).await
+}
+
+/// Build the URL of the page on the instance where the user authorises Fediurl
+pub(super) fn authorize_url(
+    instance: &Instance,
+    redirect_uri: &str,
+    scopes: &str,
+) -> Result<Url, FediurlError> {
+    let mut auth_url = instance.url().join("/oauth/authorize")?;
+    auth_url
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &instance.client_id)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("scope", scopes);
+    Ok(auth_url)
+}
+
+/// Returns true if the error was caused by a failure to establish a TLS connection
+fn is_tls_error(err: &reqwest::Error) -> bool {
+    // rustls errors are reported as `InvalidData` IO errors
+    let mut source = error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::InvalidData {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl From<FediurlError> for RegistrationError {
+    fn from(err: FediurlError) -> Self {
+        // The domain is reported along with registration errors, so the request details are only
+        // kept for errors that aren't otherwise described
+        let context = err.context().cloned();
+        match err.into_inner() {
+            FediurlError::Timeout => RegistrationError::Timeout,
+            FediurlError::Http(err) if err.is_timeout() => RegistrationError::Timeout,
+            FediurlError::Http(err) if err.is_decode() => RegistrationError::InvalidResponse(err),
+            FediurlError::Http(err) if is_tls_error(&err) => RegistrationError::Tls(err),
+            FediurlError::Http(err) if err.is_connect() => RegistrationError::Connect(err),
+            FediurlError::NotFound(_) => RegistrationError::NotSupported,
+            FediurlError::UpstreamClient(err) | FediurlError::UpstreamServer(err)
+                if matches!(err.status, 405 | 501) =>
+            {
+                RegistrationError::NotSupported
+            }
+            FediurlError::Unauthorized(err)
+            | FediurlError::UpstreamClient(err)
+            | FediurlError::UpstreamServer(err) => RegistrationError::Rejected(err),
+            err => RegistrationError::Other(match context {
+                Some(context) => err.with_context(context),
+                None => err,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::MissingCredentials => {
+                f.write_str("the instance did not provide credentials for Fediurl")
+            }
+            RegistrationError::InvalidResponse(_) => {
+                f.write_str("the instance sent a response that Fediurl did not understand")
+            }
+            RegistrationError::Timeout => f.write_str("the instance did not respond in time"),
+            RegistrationError::Tls(_) => f.write_str(
+                "a secure connection to the instance could not be established, \
+                it may have an invalid certificate",
+            ),
+            RegistrationError::Connect(_) => {
+                f.write_str("the instance could not be reached, check that the domain is correct")
+            }
+            RegistrationError::NotSupported => f.write_str(
+                "the instance does not appear to be running Mastodon or compatible software",
+            ),
+            RegistrationError::Rejected(err) => {
+                write!(
+                    f,
+                    "the instance declined to register Fediurl: {}",
+                    err.error_description
+                )
+            }
+            RegistrationError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for RegistrationError {}